@@ -0,0 +1,544 @@
+use crate::pair::{PairField, QaPair};
+use crate::retry::parse_retry_after;
+use chrono::Utc;
+use http_req::{
+    request::{Method, Request},
+    uri::Uri,
+};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::env;
+use std::time::Duration;
+
+pub const DEFAULT_API_BASE: &str = "https://api.airtable.com/v0";
+/// Column that records are upserted on unless `airtable_key_field` says
+/// otherwise.
+pub const DEFAULT_KEY_FIELD: &str = "Pair Hash";
+/// What earlier versions fell back to when the variables were unset. They are
+/// only used when `AIRTABLE_USE_DEFAULTS` opts in.
+const LEGACY_TOKEN_NAME: &str = "github";
+const LEGACY_BASE_ID: &str = "appmhvMGsMRPmuUWJ";
+const LEGACY_TABLE_NAME: &str = "mention";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Which `QaPair` fields are written to which Airtable columns. Fields that
+/// are not mapped, or that a pair lacks, are left out of the record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldMap(pub BTreeMap<PairField, String>);
+
+impl Default for FieldMap {
+    /// The "Question" and "Answer" columns only.
+    fn default() -> Self {
+        FieldMap(
+            [PairField::Question, PairField::Answer]
+                .into_iter()
+                .map(|field| (field, field.default_column().to_string()))
+                .collect(),
+        )
+    }
+}
+
+impl FieldMap {
+    /// Every field, under its default column name.
+    pub fn all() -> Self {
+        FieldMap(
+            PairField::ALL
+                .into_iter()
+                .map(|field| (field, field.default_column().to_string()))
+                .collect(),
+        )
+    }
+
+    /// Parses either "all" or a JSON object from field name to column name,
+    /// e.g. `{"question":"Question","chunk_index":"Chunk Index"}`.
+    pub fn parse(spec: &str) -> anyhow::Result<Self> {
+        if spec.trim() == "all" {
+            return Ok(FieldMap::all());
+        }
+        let map: BTreeMap<PairField, String> = serde_json::from_str(spec).map_err(|e| {
+            let names: Vec<String> = PairField::ALL
+                .iter()
+                .map(|field| serde_json::to_string(field).expect("field name"))
+                .collect();
+            anyhow::anyhow!(
+                "expected \"all\" or a JSON object mapping any of {} to column names: {}",
+                names.join(", "),
+                e
+            )
+        })?;
+        if map.is_empty() {
+            anyhow::bail!("the field map is empty");
+        }
+        if let Some((field, _)) = map.iter().find(|(_, column)| column.trim().is_empty()) {
+            anyhow::bail!("field {:?} is mapped to an empty column name", field);
+        }
+        Ok(FieldMap(map))
+    }
+
+    /// Reads `airtable_fields`, defaulting to the Question and Answer columns.
+    pub fn from_env() -> anyhow::Result<Self> {
+        FieldMap::from_var(env::var("airtable_fields").ok())
+    }
+
+    fn from_var(spec: Option<String>) -> anyhow::Result<Self> {
+        match spec {
+            Some(spec) => FieldMap::parse(&spec)
+                .map_err(|e| anyhow::anyhow!("invalid airtable_fields: {}", e)),
+            None => Ok(FieldMap::default()),
+        }
+    }
+
+    /// The Airtable `fields` object for `pair`.
+    pub fn record(&self, pair: &QaPair) -> Value {
+        let fields: Map<String, Value> = self
+            .0
+            .iter()
+            .filter_map(|(field, column)| field.value(pair).map(|value| (column.clone(), value)))
+            .collect();
+        Value::Object(fields)
+    }
+}
+
+/// Where and how pairs are written to Airtable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AirtableConfig {
+    pub base_id: String,
+    pub table: String,
+    /// Personal access token for the REST API. Writes are only checked, and
+    /// upserted, when this is set.
+    pub api_key: Option<String>,
+    /// flows.network Airtable integration used when there is no `api_key`.
+    pub token_name: Option<String>,
+    pub fields: FieldMap,
+    /// Column holding each pair's `pair_hash`. When set, records are
+    /// upserted on it, so writing a pair again does not duplicate it.
+    pub key_column: Option<String>,
+}
+
+impl AirtableConfig {
+    /// Reads `airtable_base_id`, `airtable_table_name`, `airtable_api_key` or
+    /// `airtable_token_name`, `airtable_fields` and `airtable_key_field`,
+    /// which may be set empty to insert records without upserting.
+    ///
+    /// The base, the table and one of the credentials are required. The error
+    /// lists every one that is missing, unless `AIRTABLE_USE_DEFAULTS` is set
+    /// to `true`, which fills them with the values earlier versions used.
+    pub fn from_env() -> anyhow::Result<Self> {
+        AirtableConfig::from_vars(|name| env::var(name).ok())
+    }
+
+    /// `from_env`, reading each variable through `var`.
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> anyhow::Result<Self> {
+        let non_empty_var = |name: &str| var(name).filter(|value| !value.trim().is_empty());
+        let use_defaults = matches!(
+            var("AIRTABLE_USE_DEFAULTS").as_deref(),
+            Some("1" | "true" | "yes")
+        );
+        let mut missing = Vec::new();
+        let mut defaulted = Vec::new();
+        let mut require = |name: &'static str, legacy: &str| match non_empty_var(name) {
+            Some(value) => value,
+            None if use_defaults => {
+                defaulted.push(name);
+                legacy.to_string()
+            }
+            None => {
+                missing.push(name);
+                String::new()
+            }
+        };
+        let base_id = require("airtable_base_id", LEGACY_BASE_ID);
+        let table = require("airtable_table_name", LEGACY_TABLE_NAME);
+        let api_key = non_empty_var("airtable_api_key");
+        let token_name = match non_empty_var("airtable_token_name") {
+            Some(token_name) => Some(token_name),
+            None if api_key.is_some() => None,
+            None if use_defaults => {
+                defaulted.push("airtable_token_name");
+                Some(LEGACY_TOKEN_NAME.to_string())
+            }
+            None => {
+                missing.push("airtable_api_key or airtable_token_name");
+                None
+            }
+        };
+
+        if !missing.is_empty() {
+            anyhow::bail!(
+                "missing Airtable configuration: {}. Set them, set SINK to none, or set AIRTABLE_USE_DEFAULTS to true to use the built-in defaults",
+                missing.join(", ")
+            );
+        }
+        if !defaulted.is_empty() {
+            log::warn!(
+                "Using built-in Airtable defaults for {}.",
+                defaulted.join(", ")
+            );
+        }
+        if !base_id.starts_with("app") {
+            anyhow::bail!(
+                "airtable_base_id '{}' is not a base ID, which starts with \"app\"",
+                base_id
+            );
+        }
+
+        let key_column = match var("airtable_key_field") {
+            Some(column) if column.trim().is_empty() => None,
+            Some(column) => Some(column),
+            None => Some(DEFAULT_KEY_FIELD.to_string()),
+        };
+        Ok(AirtableConfig {
+            base_id,
+            table,
+            api_key,
+            token_name,
+            fields: FieldMap::from_var(var("airtable_fields"))?,
+            key_column,
+        })
+    }
+
+    /// Checks that the table has `key_column`, so that a missing column fails
+    /// the run up front instead of every upsert batch. Only the REST API
+    /// upserts, so there is nothing to check without an API key. A check
+    /// that fails for a transient reason is logged and skipped.
+    pub fn check_key_column(&self) -> anyhow::Result<()> {
+        let (Some(client), Some(key_column)) = (self.client(), &self.key_column) else {
+            return Ok(());
+        };
+        match client.check_field(key_column) {
+            Ok(()) => Ok(()),
+            Err(e) if e.is_retryable() => {
+                log::warn!(
+                    "Could not check the Airtable key column '{}': {}",
+                    key_column,
+                    e
+                );
+                Ok(())
+            }
+            Err(e) if e.status == Some(422) => anyhow::bail!(
+                "Airtable table '{}' has no '{}' column to upsert on. Add it as a text column, set airtable_key_field to an existing column, or set airtable_key_field empty to insert without upserting: {}",
+                self.table,
+                key_column,
+                e
+            ),
+            Err(e) => anyhow::bail!("failed to read Airtable table '{}': {}", self.table, e),
+        }
+    }
+
+    /// REST client for the configured table, when there is an API key.
+    pub fn client(&self) -> Option<AirtableClient> {
+        self.api_key
+            .as_ref()
+            .map(|api_key| AirtableClient::new(api_key, &self.base_id, &self.table))
+    }
+}
+
+/// Why an Airtable write failed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AirtableError {
+    /// HTTP status, or `None` when the request never got a response.
+    pub status: Option<u16>,
+    pub message: String,
+    /// How long Airtable asked us to wait, from `Retry-After`.
+    pub retry_after: Option<Duration>,
+}
+
+impl AirtableError {
+    /// Rate limits, server errors and transport failures may succeed on a
+    /// later attempt; rejected fields, bad tokens and unknown tables never do.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self.status, None | Some(429) | Some(500..=599))
+    }
+}
+
+impl std::fmt::Display for AirtableError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.status {
+            Some(status) => write!(f, "Airtable returned {}: {}", status, self.message),
+            None => write!(f, "Airtable request failed: {}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for AirtableError {}
+
+/// Writes batches of records to one table.
+pub trait RecordWriter {
+    /// Creates one record per item of `fields`.
+    fn create_records(&self, fields: &[Value]) -> Result<(), AirtableError>;
+
+    /// Updates the record whose `key_column` matches each item of `fields`,
+    /// or creates one when none does, so writing the same records again
+    /// leaves the table unchanged.
+    fn upsert_records(&self, fields: &[Value], key_column: &str) -> Result<(), AirtableError>;
+}
+
+/// Writes records to one Airtable table through the REST API, so that every
+/// write can be checked.
+#[derive(Debug, Clone)]
+pub struct AirtableClient {
+    pub api_base: String,
+    pub api_key: String,
+    pub base_id: String,
+    pub table: String,
+}
+
+impl AirtableClient {
+    pub fn new(
+        api_key: impl Into<String>,
+        base_id: impl Into<String>,
+        table: impl Into<String>,
+    ) -> Self {
+        AirtableClient {
+            api_base: DEFAULT_API_BASE.to_string(),
+            api_key: api_key.into(),
+            base_id: base_id.into(),
+            table: table.into(),
+        }
+    }
+
+    fn url(&self) -> String {
+        format!(
+            "{}/{}/{}",
+            self.api_base.trim_end_matches('/'),
+            self.base_id,
+            percent_encode(&self.table)
+        )
+    }
+
+    /// Reads at most one record's `field`. Airtable answers 422 when the
+    /// table has no such field.
+    pub fn check_field(&self, field: &str) -> Result<(), AirtableError> {
+        let url = format!(
+            "{}?maxRecords=1&fields%5B%5D={}",
+            self.url(),
+            percent_encode(field)
+        );
+        self.send(Method::GET, &url, None)
+    }
+
+    fn send(&self, method: Method, url: &str, body: Option<Value>) -> Result<(), AirtableError> {
+        let uri = Uri::try_from(url).map_err(|e| AirtableError {
+            status: None,
+            message: format!("invalid Airtable URL '{}': {}", url, e),
+            retry_after: None,
+        })?;
+        let body = body
+            .map(|body| serde_json::to_vec(&body).expect("failed to serialize Airtable records"));
+        let bearer = format!("Bearer {}", self.api_key);
+
+        let mut writer = Vec::new();
+        let mut req = Request::new(&uri);
+        req.method(method)
+            .header("Authorization", &bearer)
+            .timeout(Some(REQUEST_TIMEOUT));
+        if let Some(body) = &body {
+            req.header("Content-Type", "application/json")
+                .header("Content-Length", &body.len())
+                .body(body);
+        }
+        let res = req.send(&mut writer).map_err(|e| AirtableError {
+            status: None,
+            message: e.to_string(),
+            retry_after: None,
+        })?;
+
+        if !res.status_code().is_success() {
+            let retry_after = res
+                .headers()
+                .get("Retry-After")
+                .and_then(|v| parse_retry_after(v, Utc::now()));
+            return Err(AirtableError {
+                status: Some(u16::from(res.status_code())),
+                message: String::from_utf8_lossy(&writer).into_owned(),
+                retry_after,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl RecordWriter for AirtableClient {
+    fn create_records(&self, fields: &[Value]) -> Result<(), AirtableError> {
+        let records: Vec<Value> = fields
+            .iter()
+            .map(|fields| serde_json::json!({ "fields": fields }))
+            .collect();
+        self.send(
+            Method::POST,
+            &self.url(),
+            Some(serde_json::json!({ "records": records, "typecast": true })),
+        )
+    }
+
+    fn upsert_records(&self, fields: &[Value], key_column: &str) -> Result<(), AirtableError> {
+        let records: Vec<Value> = fields
+            .iter()
+            .map(|fields| serde_json::json!({ "fields": fields }))
+            .collect();
+        self.send(
+            Method::PATCH,
+            &self.url(),
+            Some(serde_json::json!({
+                "performUpsert": { "fieldsToMergeOn": [key_column] },
+                "records": records,
+                "typecast": true,
+            })),
+        )
+    }
+}
+
+/// Percent-encodes a table name for use as a path segment.
+fn percent_encode(segment: &str) -> String {
+    let mut out = String::new();
+    for byte in segment.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(byte as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pair::test_pair;
+    use serde_json::json;
+
+    #[test]
+    fn parses_all_fields() {
+        let map = FieldMap::parse(" all ").unwrap();
+        assert_eq!(map, FieldMap::all());
+        assert_eq!(map.0.len(), PairField::ALL.len());
+        assert_eq!(map.0[&PairField::ChunkIndex], "Chunk Index");
+    }
+
+    #[test]
+    fn parses_a_json_field_map() {
+        let map = FieldMap::parse(r#"{"question":"Q","chunk_index":"Index"}"#).unwrap();
+        assert_eq!(
+            map.0.into_iter().collect::<Vec<_>>(),
+            vec![
+                (PairField::Question, String::from("Q")),
+                (PairField::ChunkIndex, String::from("Index")),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_field_maps() {
+        assert!(FieldMap::parse("{}").is_err());
+        assert!(FieldMap::parse(r#"{"question":" "}"#).is_err());
+        let err = FieldMap::parse(r#"{"colour":"Colour"}"#).unwrap_err();
+        assert!(err.to_string().contains("\"question\""), "{}", err);
+        assert!(FieldMap::parse("question,answer").is_err());
+    }
+
+    #[test]
+    fn records_leave_out_unmapped_and_absent_fields() {
+        let pair = test_pair("What runs pods?", "Nodes.").located(None, 3);
+        assert_eq!(
+            FieldMap::default().record(&pair),
+            json!({ "Question": "What runs pods?", "Answer": "Nodes." })
+        );
+
+        // The pair has no corpus, section or difficulty, so those columns
+        // are left out rather than written empty.
+        let map = FieldMap::parse(
+            r#"{"answer":"A","source":"Source","section":"Section","difficulty":"Difficulty","chunk_index":"Index"}"#,
+        )
+        .unwrap();
+        assert_eq!(map.record(&pair), json!({ "A": "Nodes.", "Index": 3 }));
+    }
+
+    fn from_vars(vars: &[(&str, &str)]) -> anyhow::Result<AirtableConfig> {
+        AirtableConfig::from_vars(|name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        })
+    }
+
+    #[test]
+    fn lists_every_missing_variable() {
+        let err = from_vars(&[("airtable_table_name", " ")])
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains(
+                "airtable_base_id, airtable_table_name, airtable_api_key or airtable_token_name"
+            ),
+            "{}",
+            err
+        );
+
+        let err = from_vars(&[("airtable_base_id", "appXYZ")])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("missing Airtable configuration: airtable_table_name, airtable_api_key or airtable_token_name."), "{}", err);
+    }
+
+    #[test]
+    fn falls_back_to_legacy_values_only_when_opted_in() {
+        let config = from_vars(&[("AIRTABLE_USE_DEFAULTS", "true")]).unwrap();
+        assert_eq!(config.base_id, LEGACY_BASE_ID);
+        assert_eq!(config.table, LEGACY_TABLE_NAME);
+        assert_eq!(config.token_name.as_deref(), Some(LEGACY_TOKEN_NAME));
+        assert_eq!(config.api_key, None);
+
+        // Values that are set still win over the defaults.
+        let config = from_vars(&[
+            ("AIRTABLE_USE_DEFAULTS", "1"),
+            ("airtable_table_name", "pairs"),
+            ("airtable_api_key", "pat123"),
+        ])
+        .unwrap();
+        assert_eq!(config.table, "pairs");
+        assert_eq!(config.token_name, None);
+
+        assert!(from_vars(&[("AIRTABLE_USE_DEFAULTS", "false")]).is_err());
+    }
+
+    #[test]
+    fn rejects_a_base_id_without_the_app_prefix() {
+        let err = from_vars(&[
+            ("airtable_base_id", "tblXYZ"),
+            ("airtable_table_name", "pairs"),
+            ("airtable_token_name", "github"),
+        ])
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("'tblXYZ' is not a base ID"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn reads_a_complete_configuration() {
+        let config = from_vars(&[
+            ("airtable_base_id", "appXYZ"),
+            ("airtable_table_name", "pairs"),
+            ("airtable_api_key", "pat123"),
+            ("airtable_fields", "all"),
+            ("airtable_key_field", ""),
+        ])
+        .unwrap();
+        assert_eq!(config.base_id, "appXYZ");
+        assert_eq!(config.api_key.as_deref(), Some("pat123"));
+        assert_eq!(config.fields, FieldMap::all());
+        assert_eq!(config.key_column, None);
+
+        let config = from_vars(&[
+            ("airtable_base_id", "appXYZ"),
+            ("airtable_table_name", "pairs"),
+            ("airtable_token_name", "github"),
+        ])
+        .unwrap();
+        assert_eq!(config.key_column.as_deref(), Some(DEFAULT_KEY_FIELD));
+        assert_eq!(config.fields, FieldMap::default());
+    }
+}
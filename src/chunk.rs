@@ -0,0 +1,475 @@
+use crate::markdown::chunk_markdown;
+use serde::{Deserialize, Serialize};
+use std::env;
+
+pub const DEFAULT_TARGET_TOKENS: usize = 800;
+
+/// A piece of input sent to the model, with the markdown headings it sits
+/// under when the input was chunked by structure.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Chunk {
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headings: Vec<String>,
+}
+
+impl Chunk {
+    pub fn new(text: impl Into<String>) -> Self {
+        Chunk {
+            text: text.into(),
+            headings: Vec::new(),
+        }
+    }
+
+    /// Heading path such as "Chapter 1 > Ownership", if the chunk has one.
+    pub fn breadcrumb(&self) -> Option<String> {
+        if self.headings.is_empty() {
+            None
+        } else {
+            Some(self.headings.join(" > "))
+        }
+    }
+}
+
+/// How input is cut into the chunks that are sent to the model.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum ChunkStrategy {
+    /// One chunk per blank-line separated section, or per element of a JSON
+    /// array.
+    #[default]
+    BlankLine,
+    /// Packs consecutive sections up to `target_tokens`, splitting oversized
+    /// ones on sentence boundaries. Each chunk after the first repeats up to
+    /// `overlap_tokens` of trailing sentences, or words, from the previous
+    /// one.
+    TokenBudget {
+        #[serde(default = "default_target_tokens")]
+        target_tokens: usize,
+        #[serde(default)]
+        overlap_tokens: usize,
+    },
+    /// Follows markdown structure: chunks stay within one section, fenced
+    /// code, tables and lists are kept whole, and each chunk records its
+    /// heading path.
+    Markdown {
+        #[serde(default = "default_target_tokens")]
+        target_tokens: usize,
+    },
+}
+
+fn default_target_tokens() -> usize {
+    DEFAULT_TARGET_TOKENS
+}
+
+impl ChunkStrategy {
+    /// Reads `CHUNK_STRATEGY` (`blank_line`, `token_budget` or `markdown`),
+    /// `CHUNK_TARGET_TOKENS` and `CHUNK_OVERLAP_TOKENS`.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let target_tokens = || -> anyhow::Result<usize> {
+            match env::var("CHUNK_TARGET_TOKENS") {
+                Ok(n) => n
+                    .parse()
+                    .map_err(|e| anyhow::anyhow!("invalid CHUNK_TARGET_TOKENS '{}': {}", n, e)),
+                Err(_) => Ok(DEFAULT_TARGET_TOKENS),
+            }
+        };
+        match env::var("CHUNK_STRATEGY").as_deref() {
+            Err(_) | Ok("blank_line") => Ok(ChunkStrategy::BlankLine),
+            Ok("markdown") => Ok(ChunkStrategy::Markdown {
+                target_tokens: target_tokens()?,
+            }),
+            Ok("token_budget") => {
+                let target_tokens = target_tokens()?;
+                let overlap_tokens = match env::var("CHUNK_OVERLAP_TOKENS") {
+                    Ok(n) => n.parse().map_err(|e| {
+                        anyhow::anyhow!("invalid CHUNK_OVERLAP_TOKENS '{}': {}", n, e)
+                    })?,
+                    Err(_) => 0,
+                };
+                Ok(ChunkStrategy::TokenBudget {
+                    target_tokens,
+                    overlap_tokens,
+                })
+            }
+            Ok(other) => anyhow::bail!(
+                "invalid CHUNK_STRATEGY '{}', expected blank_line, token_budget or markdown",
+                other
+            ),
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match self {
+            ChunkStrategy::BlankLine => {}
+            ChunkStrategy::TokenBudget {
+                target_tokens,
+                overlap_tokens,
+            } => {
+                if *target_tokens == 0 {
+                    anyhow::bail!("chunk target_tokens must be greater than zero");
+                }
+                if overlap_tokens >= target_tokens {
+                    anyhow::bail!(
+                        "chunk overlap_tokens ({}) must be smaller than target_tokens ({})",
+                        overlap_tokens,
+                        target_tokens
+                    );
+                }
+            }
+            ChunkStrategy::Markdown { target_tokens } => {
+                if *target_tokens == 0 {
+                    anyhow::bail!("chunk target_tokens must be greater than zero");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Splits raw text into chunks, or reads them from `input` when it already is
+/// a JSON array of pre-chunked strings, then applies `strategy`.
+pub fn load_chunks(input: &str, strategy: &ChunkStrategy) -> Vec<Chunk> {
+    let sections = if input.trim_start().starts_with('[') {
+        match serde_json::from_str::<Vec<String>>(input) {
+            Ok(chunks) => chunks,
+            Err(e) => {
+                log::debug!(
+                    "Input is not a JSON array of chunks, chunking it as text: {}",
+                    e
+                );
+                split_text_into_chunks(input)
+            }
+        }
+    } else if let ChunkStrategy::Markdown { target_tokens } = strategy {
+        // Blank-line splitting would lose the structure markdown chunking
+        // relies on, so raw text goes to it whole.
+        return chunk_markdown(input, *target_tokens);
+    } else {
+        split_text_into_chunks(input)
+    };
+    apply_strategy(sections, strategy)
+}
+
+pub fn apply_strategy(sections: Vec<String>, strategy: &ChunkStrategy) -> Vec<Chunk> {
+    match strategy {
+        ChunkStrategy::BlankLine => sections.into_iter().map(Chunk::new).collect(),
+        ChunkStrategy::TokenBudget {
+            target_tokens,
+            overlap_tokens,
+        } => pack_by_tokens(&sections, *target_tokens, *overlap_tokens)
+            .into_iter()
+            .map(Chunk::new)
+            .collect(),
+        ChunkStrategy::Markdown { target_tokens } => {
+            chunk_markdown(&sections.join("\n\n"), *target_tokens)
+        }
+    }
+}
+
+/// Rough token count, at about four characters per token for English text.
+/// Good enough for sizing chunks without shipping a tokenizer in the wasm.
+pub fn estimate_tokens(text: &str) -> usize {
+    char_len(text).div_ceil(CHARS_PER_TOKEN)
+}
+
+/// Packs sections into chunks of at most `target_tokens`. A section that is
+/// too large on its own is split into sentences first, and a sentence that is
+/// still too large is split on words. Each chunk after the first starts with
+/// up to `overlap_tokens` taken from the end of the previous one.
+pub fn pack_by_tokens(
+    sections: &[String],
+    target_tokens: usize,
+    overlap_tokens: usize,
+) -> Vec<String> {
+    // Sizes are tracked in characters, matching `estimate_tokens`, so the
+    // separators between packed units are accounted for exactly.
+    let max_chars = target_tokens * CHARS_PER_TOKEN;
+    let overlap_chars = overlap_tokens * CHARS_PER_TOKEN;
+
+    let mut units = Vec::new();
+    for section in sections {
+        let section = section.trim();
+        if section.is_empty() {
+            continue;
+        }
+        if char_len(section) <= max_chars {
+            units.push(section.to_string());
+            continue;
+        }
+        for sentence in split_sentences(section) {
+            if char_len(&sentence) <= max_chars {
+                units.push(sentence);
+            } else {
+                units.extend(split_words(&sentence, max_chars));
+            }
+        }
+    }
+
+    let mut res = Vec::new();
+    let mut current: Vec<String> = Vec::new();
+    // Number of leading units in `current` carried over as overlap.
+    let mut carried = 0;
+    for unit in units {
+        let unit_chars = char_len(&unit);
+        if current.len() > carried
+            && joined_len(&current) + SEPARATOR.len() + unit_chars > max_chars
+        {
+            let chunk = current.join(SEPARATOR);
+            let room = max_chars.saturating_sub(unit_chars + SEPARATOR.len());
+            current = overlap_tail(&chunk, overlap_chars.min(room))
+                .into_iter()
+                .collect();
+            carried = current.len();
+            res.push(chunk);
+        }
+        current.push(unit);
+    }
+    if current.len() > carried {
+        res.push(current.join(SEPARATOR));
+    }
+    res
+}
+
+/// Characters per token assumed by `estimate_tokens`.
+pub(crate) const CHARS_PER_TOKEN: usize = 4;
+const SEPARATOR: &str = "\n\n";
+
+fn char_len(text: &str) -> usize {
+    text.chars().count()
+}
+
+fn joined_len(units: &[String]) -> usize {
+    let chars: usize = units.iter().map(|u| char_len(u)).sum();
+    chars + SEPARATOR.len() * units.len().saturating_sub(1)
+}
+
+/// The end of `chunk` to repeat at the start of the next one: as many
+/// trailing sentences as fit in `max_chars`, or when not even the last one
+/// fits, as many of its trailing words.
+fn overlap_tail(chunk: &str, max_chars: usize) -> Option<String> {
+    let sentences: Vec<String> = chunk.split(SEPARATOR).flat_map(split_sentences).collect();
+    let mut tail: Vec<&str> = Vec::new();
+    let mut tail_chars = 0;
+    for sentence in sentences.iter().rev() {
+        let added = char_len(sentence) + usize::from(!tail.is_empty());
+        if tail_chars + added > max_chars {
+            break;
+        }
+        tail.push(sentence);
+        tail_chars += added;
+    }
+    if tail.is_empty() {
+        let last = sentences.last()?;
+        for word in last.split_whitespace().rev() {
+            let added = char_len(word) + usize::from(!tail.is_empty());
+            if tail_chars + added > max_chars {
+                break;
+            }
+            tail.push(word);
+            tail_chars += added;
+        }
+    }
+    if tail.is_empty() {
+        return None;
+    }
+    tail.reverse();
+    Some(tail.join(" "))
+}
+
+fn split_sentences(text: &str) -> Vec<String> {
+    let mut sentences = Vec::new();
+    let mut current = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        current.push(c);
+        let at_boundary =
+            matches!(c, '.' | '?' | '!') && chars.peek().is_none_or(|next| next.is_whitespace());
+        if at_boundary {
+            sentences.push(current.trim().to_string());
+            current.clear();
+        }
+    }
+    if !current.trim().is_empty() {
+        sentences.push(current.trim().to_string());
+    }
+    sentences
+}
+
+fn split_words(text: &str, max_chars: usize) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut current = String::new();
+    for word in text.split_whitespace() {
+        if !current.is_empty() && char_len(&current) + 1 + char_len(word) > max_chars {
+            pieces.push(std::mem::take(&mut current));
+        }
+        if char_len(word) > max_chars {
+            // A single word longer than a chunk, e.g. a long URL or hash.
+            let chars: Vec<char> = word.chars().collect();
+            for piece in chars.chunks(max_chars) {
+                pieces.push(piece.iter().collect());
+            }
+            continue;
+        }
+        if !current.is_empty() {
+            current.push(' ');
+        }
+        current.push_str(word);
+    }
+    if !current.is_empty() {
+        pieces.push(current);
+    }
+    pieces
+}
+
+/// Splits text into sections separated by one or more blank lines.
+///
+/// No text is dropped: every non-blank line lands in exactly one chunk, in
+/// order, including a final section that is not followed by a blank line.
+/// Only the blank separator lines themselves are left out.
+pub fn split_text_into_chunks(raw_text: &str) -> Vec<String> {
+    let mut res = Vec::new();
+    let mut current_section = String::new();
+
+    for line in raw_text.lines() {
+        if line.trim().is_empty() {
+            if !current_section.is_empty() {
+                res.push(std::mem::take(&mut current_section));
+            }
+        } else {
+            current_section.push_str(line);
+            current_section.push('\n');
+        }
+    }
+    if !current_section.is_empty() {
+        res.push(current_section);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn non_whitespace(text: &str) -> String {
+        text.chars().filter(|c| !c.is_whitespace()).collect()
+    }
+
+    proptest! {
+        #[test]
+        fn split_drops_no_text(text in "[a-z .\r\n\t]{0,200}") {
+            let chunks = split_text_into_chunks(&text);
+            prop_assert_eq!(non_whitespace(&chunks.concat()), non_whitespace(&text));
+            prop_assert!(chunks.iter().all(|chunk| !chunk.trim().is_empty()));
+        }
+
+        #[test]
+        fn split_drops_no_text_from_any_input(text in any::<String>()) {
+            let chunks = split_text_into_chunks(&text);
+            prop_assert_eq!(non_whitespace(&chunks.concat()), non_whitespace(&text));
+        }
+    }
+
+    #[test]
+    fn split_keeps_section_without_trailing_newline() {
+        assert_eq!(
+            split_text_into_chunks("first\n\nsecond line"),
+            vec!["first\n", "second line\n"]
+        );
+    }
+
+    #[test]
+    fn split_treats_runs_of_blank_lines_as_one_break() {
+        assert_eq!(
+            split_text_into_chunks("\n\none\n\n\n  \n\ntwo\nthree\n\n\n"),
+            vec!["one\n", "two\nthree\n"]
+        );
+    }
+
+    #[test]
+    fn split_handles_crlf_line_endings() {
+        assert_eq!(
+            split_text_into_chunks("one\r\ntwo\r\n\r\nthree\r\n"),
+            vec!["one\ntwo\n", "three\n"]
+        );
+    }
+
+    fn sections(texts: &[&str]) -> Vec<String> {
+        texts.iter().map(|text| text.to_string()).collect()
+    }
+
+    #[test]
+    fn pack_merges_small_sections() {
+        let chunks = pack_by_tokens(
+            &sections(&["Pods run.", "Nodes host pods.", "Done."]),
+            100,
+            0,
+        );
+        assert_eq!(chunks, vec!["Pods run.\n\nNodes host pods.\n\nDone."]);
+    }
+
+    #[test]
+    fn pack_splits_oversized_sections_on_sentences() {
+        let section = "Pods run containers. Services route traffic. Volumes hold data.";
+        let chunks = pack_by_tokens(&sections(&[section]), 12, 0);
+        assert_eq!(
+            chunks,
+            vec![
+                "Pods run containers.\n\nServices route traffic.",
+                "Volumes hold data."
+            ]
+        );
+    }
+
+    #[test]
+    fn pack_splits_oversized_sentences_on_words() {
+        let section = "one two three four five six seven eight nine ten eleven twelve";
+        let chunks = pack_by_tokens(&sections(&[section]), 4, 0);
+        assert!(
+            chunks.iter().all(|chunk| char_len(chunk) <= 16),
+            "{:?}",
+            chunks
+        );
+        assert_eq!(
+            chunks.join(" ").split_whitespace().collect::<Vec<_>>(),
+            section.split_whitespace().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn pack_overlaps_with_part_of_a_section() {
+        // Each section is larger than the overlap, so only trailing
+        // sentences of it can be repeated.
+        let section =
+            "Pods run containers on nodes. Services route the traffic. Volumes hold data.";
+        let chunks = pack_by_tokens(&sections(&[section; 6]), 60, 10);
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(estimate_tokens(chunk) <= 60, "{:?}", chunk);
+        }
+        for pair in chunks.windows(2) {
+            let (overlap, _) = pair[1].split_once(SEPARATOR).unwrap();
+            assert_eq!(overlap, "Volumes hold data.");
+            assert!(pair[0].ends_with(overlap));
+        }
+    }
+
+    #[test]
+    fn pack_overlaps_with_words_when_no_sentence_fits() {
+        let section = "alpha beta gamma delta epsilon zeta eta theta iota kappa lambda mu";
+        let chunks = pack_by_tokens(&sections(&[section, section]), 20, 3);
+        assert_eq!(chunks.len(), 2);
+        let (overlap, _) = chunks[1].split_once(SEPARATOR).unwrap();
+        assert_eq!(overlap, "lambda mu");
+        assert!(chunks[0].ends_with(overlap));
+    }
+
+    #[test]
+    fn pack_without_overlap_repeats_nothing() {
+        let section =
+            "Pods run containers on nodes. Services route the traffic. Volumes hold data.";
+        let chunks = pack_by_tokens(&sections(&[section; 3]), 25, 0);
+        assert_eq!(chunks, vec![section; 3]);
+    }
+}
@@ -0,0 +1,47 @@
+use crate::chunk::{load_chunks, Chunk, ChunkStrategy};
+
+/// Corpus used when neither the scheduled payload nor `CORPUS` names one.
+pub const DEFAULT_CORPUS: &str = "rust_chapter";
+
+#[derive(Debug)]
+pub struct Corpus {
+    pub name: &'static str,
+    pub contents: &'static str,
+}
+
+static CORPORA: &[Corpus] = &[
+    Corpus {
+        name: "rust_chapter",
+        contents: include_str!("../rust_chapter.json"),
+    },
+    Corpus {
+        name: "k8s",
+        contents: include_str!("../k8s.json"),
+    },
+    Corpus {
+        name: "test",
+        contents: include_str!("../test.txt"),
+    },
+];
+
+impl Corpus {
+    /// Bundled corpora are either JSON arrays of chunks or raw text; both
+    /// are accepted.
+    pub fn chunks(&self, strategy: &ChunkStrategy) -> Vec<Chunk> {
+        load_chunks(self.contents, strategy)
+    }
+}
+
+pub fn corpus_names() -> Vec<&'static str> {
+    CORPORA.iter().map(|c| c.name).collect()
+}
+
+pub fn get_corpus(name: &str) -> anyhow::Result<&'static Corpus> {
+    CORPORA.iter().find(|c| c.name == name).ok_or_else(|| {
+        anyhow::anyhow!(
+            "unknown corpus '{}', available corpora: {}",
+            name,
+            corpus_names().join(", ")
+        )
+    })
+}
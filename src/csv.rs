@@ -0,0 +1,224 @@
+use crate::error::{Error, Result};
+use crate::pair::{PairField, QaPair};
+use crate::sink::{PairSink, WriteReport};
+use async_trait::async_trait;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+
+pub const DEFAULT_CSV_FILE: &str = "qa_pairs.csv";
+
+/// Writes pairs as RFC 4180 CSV: comma separated, CRLF line endings, and
+/// fields with commas, quotes or line breaks quoted, so multi-line answers
+/// survive a round trip.
+pub struct CsvSink<W: Write> {
+    out: W,
+    /// Each column's field and header.
+    columns: Vec<(PairField, String)>,
+    /// Whether a header row is still to be written before the first record.
+    header_pending: bool,
+}
+
+impl<W: Write> CsvSink<W> {
+    /// Writes `columns`, under their default names, to `out`, starting with
+    /// a header row when `header` is set.
+    pub fn new(out: W, columns: &[PairField], header: bool) -> Self {
+        CsvSink {
+            out,
+            columns: columns
+                .iter()
+                .map(|field| (*field, field.default_column().to_string()))
+                .collect(),
+            header_pending: header,
+        }
+    }
+
+    /// Writes the header row now, if it is still to be written, rather than
+    /// before the first record.
+    pub fn write_header(&mut self) -> io::Result<()> {
+        if self.header_pending {
+            let header: Vec<&str> = self.columns.iter().map(|(_, name)| name.as_str()).collect();
+            write_record(&mut self.out, &header)?;
+            self.header_pending = false;
+        }
+        Ok(())
+    }
+
+    pub fn write_pair(&mut self, pair: &QaPair) -> io::Result<()> {
+        self.write_header()?;
+        let cells: Vec<String> = self
+            .columns
+            .iter()
+            .map(|(field, _)| field.text(pair).unwrap_or_default())
+            .collect();
+        let cells: Vec<&str> = cells.iter().map(String::as_str).collect();
+        write_record(&mut self.out, &cells)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl CsvSink<File> {
+    /// Appends to the file named by `CSV_FILE` (default `qa_pairs.csv`).
+    /// `CSV_COLUMNS` lists the fields to write, comma separated (default
+    /// `question,answer`), and `CSV_HEADER` set to `false` leaves out the
+    /// header row, which is also skipped when appending to a non-empty file.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let columns = match env::var("CSV_COLUMNS") {
+            Ok(columns) => parse_columns(&columns)
+                .map_err(|e| anyhow::anyhow!("invalid CSV_COLUMNS: {}", e))?,
+            Err(_) => vec![PairField::Question, PairField::Answer],
+        };
+        let header = !matches!(env::var("CSV_HEADER").as_deref(), Ok("0" | "false" | "no"));
+        let path = env::var("CSV_FILE").unwrap_or(DEFAULT_CSV_FILE.to_string());
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| anyhow::anyhow!("failed to open CSV file '{}': {}", path, e))?;
+        let empty = file.metadata().map(|m| m.len() == 0).unwrap_or(true);
+        Ok(CsvSink::new(file, &columns, header && empty))
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Write> PairSink for CsvSink<W> {
+    async fn write(&mut self, pairs: &[QaPair]) -> Result<WriteReport> {
+        for pair in pairs {
+            self.write_pair(pair)
+                .map_err(|e| Error::Sink(format!("failed to write CSV: {}", e)))?;
+        }
+        Ok(WriteReport {
+            written: pairs.len(),
+            ..WriteReport::default()
+        })
+    }
+
+    async fn flush(&mut self) -> Result<WriteReport> {
+        self.out
+            .flush()
+            .map_err(|e| Error::Sink(format!("failed to write CSV: {}", e)))?;
+        Ok(WriteReport::default())
+    }
+}
+
+/// Parses a comma-separated list of field names such as
+/// `question,answer,chunk_index`.
+pub fn parse_columns(spec: &str) -> anyhow::Result<Vec<PairField>> {
+    let columns = spec
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            serde_json::from_value(serde_json::Value::String(name.to_string()))
+                .map_err(|_| anyhow::anyhow!("unknown field '{}'", name))
+        })
+        .collect::<anyhow::Result<Vec<PairField>>>()?;
+    if columns.is_empty() {
+        anyhow::bail!("no columns given");
+    }
+    Ok(columns)
+}
+
+fn write_record(out: &mut impl Write, cells: &[&str]) -> io::Result<()> {
+    let line: Vec<String> = cells.iter().map(|cell| field(cell)).collect();
+    write!(out, "{}\r\n", line.join(","))
+}
+
+fn field(cell: &str) -> String {
+    if cell.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", cell.replace('"', "\"\""))
+    } else {
+        cell.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pair::test_pair;
+
+    /// Reads RFC 4180 records back, honouring quoted commas, quotes and line
+    /// breaks.
+    fn parse(csv: &str) -> Vec<Vec<String>> {
+        let mut records = Vec::new();
+        let mut record = Vec::new();
+        let mut cell = String::new();
+        let mut quoted = false;
+        let mut chars = csv.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' if quoted && chars.peek() == Some(&'"') => {
+                    cell.push('"');
+                    chars.next();
+                }
+                '"' => quoted = !quoted,
+                ',' if !quoted => record.push(std::mem::take(&mut cell)),
+                '\r' if !quoted && chars.peek() == Some(&'\n') => {
+                    chars.next();
+                    record.push(std::mem::take(&mut cell));
+                    records.push(std::mem::take(&mut record));
+                }
+                c => cell.push(c),
+            }
+        }
+        records
+    }
+
+    fn write(pairs: &[QaPair], header: bool) -> String {
+        let mut sink = CsvSink::new(
+            Vec::new(),
+            &[PairField::Question, PairField::Answer],
+            header,
+        );
+        for pair in pairs {
+            sink.write_pair(pair).unwrap();
+        }
+        String::from_utf8(sink.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn quoted_fields_round_trip() {
+        let answer = "Say \"hello\", then wait.\nA second line, with a comma.\r\nAnd a third.";
+        let csv = write(&[test_pair("What, exactly?", answer)], true);
+        assert_eq!(
+            parse(&csv),
+            vec![
+                vec![String::from("Question"), String::from("Answer")],
+                vec![String::from("What, exactly?"), answer.to_string()],
+            ]
+        );
+    }
+
+    #[test]
+    fn records_end_with_crlf() {
+        let csv = write(&[test_pair("One?", "1."), test_pair("Two?", "2.")], true);
+        assert_eq!(csv, "Question,Answer\r\nOne?,1.\r\nTwo?,2.\r\n");
+    }
+
+    #[test]
+    fn header_is_optional() {
+        let csv = write(&[test_pair("One?", "1.")], false);
+        assert_eq!(csv, "One?,1.\r\n");
+        // The header is written once, before the first record only.
+        let csv = write(&[test_pair("One?", "1."), test_pair("Two?", "2.")], true);
+        assert_eq!(csv.matches("Question,Answer").count(), 1);
+    }
+
+    #[test]
+    fn parses_column_lists() {
+        assert_eq!(
+            parse_columns(" question, answer ,chunk_index,").unwrap(),
+            vec![
+                PairField::Question,
+                PairField::Answer,
+                PairField::ChunkIndex
+            ]
+        );
+        let err = parse_columns("question,colour").unwrap_err();
+        assert!(err.to_string().contains("colour"), "{}", err);
+        assert!(parse_columns(" , ").is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use crate::llm::LlmError;
+use std::fmt;
+use std::time::Duration;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Why generating or storing the pairs for a chunk failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// The LLM backend failed, after any retries.
+    Api(LlmError),
+    /// The chat completion request could not be built, so it was never sent.
+    Request(String),
+    /// The backend kept rate limiting us until retries ran out.
+    RateLimit {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    /// The reply was not valid JSON.
+    MalformedJson { error: String, content: String },
+    /// The reply was valid JSON but not in the requested shape.
+    SchemaMismatch(String),
+    /// The reply was cut off at `max_tokens`.
+    TruncatedOutput,
+    /// The reply had no content at all.
+    EmptyResponse,
+    /// Writing pairs to a sink failed.
+    Sink(String),
+}
+
+impl Error {
+    /// Short, stable name of the category, for logs and run reports.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Api(_) => "api",
+            Error::Request(_) => "request",
+            Error::RateLimit { .. } => "rate_limit",
+            Error::MalformedJson { .. } => "malformed_json",
+            Error::SchemaMismatch(_) => "schema_mismatch",
+            Error::TruncatedOutput => "truncated_output",
+            Error::EmptyResponse => "empty_response",
+            Error::Sink(_) => "sink",
+        }
+    }
+
+    /// Whether the error will recur for every chunk, so the run should stop
+    /// instead of trying the rest.
+    pub fn aborts_run(&self) -> bool {
+        matches!(self, Error::Api(e) if e.is_auth())
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Api(e) => write!(f, "{}", e),
+            Error::Request(message) => write!(f, "failed to build request: {}", message),
+            Error::RateLimit { message, .. } => write!(f, "rate limited: {}", message),
+            Error::MalformedJson { error, .. } => write!(f, "reply is not valid JSON: {}", error),
+            Error::SchemaMismatch(message) => {
+                write!(f, "reply does not match the expected schema: {}", message)
+            }
+            Error::TruncatedOutput => write!(f, "reply was cut off at max_tokens"),
+            Error::EmptyResponse => write!(f, "reply has no content"),
+            Error::Sink(message) => write!(f, "failed to write pairs: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<LlmError> for Error {
+    fn from(e: LlmError) -> Self {
+        match e {
+            LlmError::Retryable {
+                status: Some(429),
+                message,
+                retry_after,
+            } => Error::RateLimit {
+                message,
+                retry_after,
+            },
+            e => Error::Api(e),
+        }
+    }
+}
+
+impl From<async_openai::error::OpenAIError> for Error {
+    fn from(e: async_openai::error::OpenAIError) -> Self {
+        Error::Request(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::error::OpenAIError;
+
+    #[test]
+    fn build_failures_are_not_api_errors() {
+        let e = Error::from(OpenAIError::InvalidArgument(String::from("bad role")));
+        assert!(matches!(e, Error::Request(_)));
+        assert_eq!(e.kind(), "request");
+        assert!(!e.aborts_run());
+    }
+}
@@ -0,0 +1,161 @@
+use crate::error::{Error, Result};
+use crate::pair::QaPair;
+use crate::sink::{PairSink, WriteReport};
+use async_trait::async_trait;
+use serde_json::json;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+
+pub const DEFAULT_FINETUNE_FILE: &str = "qa_pairs.jsonl";
+pub const DEFAULT_SYSTEM_MESSAGE: &str = "You are a helpful assistant.";
+
+/// Writes pairs as JSONL in the OpenAI chat fine-tuning format: one
+/// `{"messages":[system, user, assistant]}` record per pair, with the
+/// question as the user message and the answer as the assistant message.
+pub struct FineTuneSink<W: Write> {
+    out: W,
+    /// Left out of each record when `None`.
+    system_message: Option<String>,
+    /// Whether the user message starts with the chunk the pair came from.
+    include_context: bool,
+}
+
+impl<W: Write> FineTuneSink<W> {
+    /// A blank `system_message` is treated as none.
+    pub fn new(out: W, system_message: Option<String>, include_context: bool) -> Self {
+        FineTuneSink {
+            out,
+            system_message: system_message.filter(|message| !message.trim().is_empty()),
+            include_context,
+        }
+    }
+
+    pub fn write_pair(&mut self, pair: &QaPair) -> io::Result<()> {
+        let mut messages = Vec::new();
+        if let Some(system_message) = &self.system_message {
+            messages.push(json!({ "role": "system", "content": system_message }));
+        }
+        let user = if self.include_context {
+            format!(
+                "Context:\n{}\n\nQuestion: {}",
+                pair.chunk_text, pair.question
+            )
+        } else {
+            pair.question.clone()
+        };
+        messages.push(json!({ "role": "user", "content": user }));
+        messages.push(json!({ "role": "assistant", "content": pair.answer }));
+        writeln!(self.out, "{}", json!({ "messages": messages }))
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl FineTuneSink<File> {
+    /// Appends to the file named by `FINETUNE_FILE` (default
+    /// `qa_pairs.jsonl`). `FINETUNE_SYSTEM_MESSAGE` replaces the default
+    /// system message, or drops it when set empty, and
+    /// `FINETUNE_INCLUDE_CONTEXT` set to `true` puts the source chunk before
+    /// each question.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let system_message =
+            env::var("FINETUNE_SYSTEM_MESSAGE").unwrap_or(DEFAULT_SYSTEM_MESSAGE.to_string());
+        let include_context = matches!(
+            env::var("FINETUNE_INCLUDE_CONTEXT").as_deref(),
+            Ok("1" | "true" | "yes")
+        );
+        let path = env::var("FINETUNE_FILE").unwrap_or(DEFAULT_FINETUNE_FILE.to_string());
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| anyhow::anyhow!("failed to open fine-tuning file '{}': {}", path, e))?;
+        Ok(FineTuneSink::new(
+            file,
+            Some(system_message),
+            include_context,
+        ))
+    }
+}
+
+#[async_trait(?Send)]
+impl<W: Write> PairSink for FineTuneSink<W> {
+    async fn write(&mut self, pairs: &[QaPair]) -> Result<WriteReport> {
+        for pair in pairs {
+            self.write_pair(pair)
+                .map_err(|e| Error::Sink(format!("failed to write fine-tuning JSONL: {}", e)))?;
+        }
+        Ok(WriteReport {
+            written: pairs.len(),
+            ..WriteReport::default()
+        })
+    }
+
+    async fn flush(&mut self) -> Result<WriteReport> {
+        self.out
+            .flush()
+            .map_err(|e| Error::Sink(format!("failed to write fine-tuning JSONL: {}", e)))?;
+        Ok(WriteReport::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pair::test_pair;
+
+    fn write(mut sink: FineTuneSink<Vec<u8>>, pair: &QaPair) -> String {
+        sink.write_pair(pair).unwrap();
+        String::from_utf8(sink.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn writes_one_chat_record_per_line() {
+        let sink = FineTuneSink::new(Vec::new(), Some(DEFAULT_SYSTEM_MESSAGE.to_string()), false);
+        let line = write(sink, &test_pair("What runs pods?", "Nodes \"run\" them."));
+        assert_eq!(
+            line,
+            concat!(
+                r#"{"messages":["#,
+                r#"{"content":"You are a helpful assistant.","role":"system"},"#,
+                r#"{"content":"What runs pods?","role":"user"},"#,
+                r#"{"content":"Nodes \"run\" them.","role":"assistant"}"#,
+                "]}\n"
+            )
+        );
+    }
+
+    #[test]
+    fn leaves_out_an_empty_system_message() {
+        let line = write(
+            FineTuneSink::new(Vec::new(), Some(String::from(" ")), false),
+            &test_pair("Q?", "A."),
+        );
+        assert_eq!(
+            line,
+            concat!(
+                r#"{"messages":["#,
+                r#"{"content":"Q?","role":"user"},"#,
+                r#"{"content":"A.","role":"assistant"}"#,
+                "]}\n"
+            )
+        );
+    }
+
+    #[test]
+    fn puts_the_chunk_before_the_question_with_context() {
+        let line = write(
+            FineTuneSink::new(Vec::new(), None, true),
+            &test_pair("Q?", "A."),
+        );
+        let record: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            record["messages"][0]["content"],
+            "Context:\nPods run containers.\n\nQuestion: Q?"
+        );
+        assert_eq!(record["messages"][1]["content"], "A.");
+    }
+}
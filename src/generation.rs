@@ -0,0 +1,408 @@
+use crate::chunk::{estimate_tokens, pack_by_tokens, Chunk};
+use crate::error::{Error, Result};
+use crate::llm::LlmBackend;
+use crate::pair::{content_hash, QaPair};
+use crate::params::GenerationParams;
+use crate::reply::{parse_pairs, repair_prompt, salvage_pairs, ReplyPair};
+use async_openai::types::{
+    ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestSystemMessageArgs,
+    ChatCompletionRequestUserMessageArgs, ChatCompletionResponseFormat,
+    ChatCompletionResponseFormatType, CreateChatCompletionRequest, CreateChatCompletionRequestArgs,
+    FinishReason,
+};
+use chrono::Utc;
+use std::env;
+
+/// Generates Q&A pairs for one chunk without writing them anywhere.
+pub async fn generate_pairs(
+    backend: &dyn LlmBackend,
+    chunk: &Chunk,
+    params: &GenerationParams,
+) -> Result<Vec<QaPair>> {
+    let pairs = generate(backend, chunk, params, 0).await?;
+    let prompt_version = prompt_version();
+    let generated_at = Utc::now();
+    Ok(pairs
+        .into_iter()
+        .map(|pair| QaPair::from_reply(pair, chunk, params, &prompt_version, generated_at))
+        .collect())
+}
+
+/// How many times a chunk is halved when a reply is cut off before even one
+/// pair is complete.
+const MAX_RESPLIT_DEPTH: u32 = 2;
+
+async fn generate(
+    backend: &dyn LlmBackend,
+    chunk: &Chunk,
+    params: &GenerationParams,
+    depth: u32,
+) -> Result<Vec<ReplyPair>> {
+    let mut pairs = Vec::new();
+    let mut continuations = 0;
+    loop {
+        let request = build_request(chunk, params, &pairs)?;
+        // Failures reach the caller, which stops the run on credential errors.
+        let chat = backend.chat(&request).await?;
+        let choice = chat
+            .choices
+            .into_iter()
+            .next()
+            .ok_or(Error::EmptyResponse)?;
+        let content = choice.message.content.unwrap_or_default();
+
+        if choice.finish_reason != Some(FinishReason::Length) {
+            // A continuation that fails to parse still leaves the pairs
+            // salvaged so far.
+            match parse_with_repair(backend, params, &request, content).await {
+                Ok(more) => pairs.extend(more),
+                Err(e) if !pairs.is_empty() => {
+                    log::warn!("Ignoring a failed continuation ({}): {}", e.kind(), e);
+                }
+                Err(e) => return Err(e),
+            }
+            return Ok(pairs);
+        }
+
+        let salvaged = salvage_pairs(&content);
+        log::warn!(
+            "Reply was cut off at max_tokens, salvaged {} Q&A pairs.",
+            salvaged.len()
+        );
+        if salvaged.is_empty() {
+            if !pairs.is_empty() {
+                return Ok(pairs);
+            }
+            return resplit(backend, chunk, params, depth).await;
+        }
+        pairs.extend(salvaged);
+        if continuations == params.max_continuations {
+            log::warn!(
+                "Reply still cut off after {} continuations, keeping {} Q&A pairs.",
+                continuations,
+                pairs.len()
+            );
+            return Ok(pairs);
+        }
+        continuations += 1;
+    }
+}
+
+fn parse_reply(content: &str) -> Result<Vec<ReplyPair>> {
+    if content.trim().is_empty() {
+        return Err(Error::EmptyResponse);
+    }
+    parse_pairs(content)
+}
+
+/// Parses a finished reply. A reply that is not valid JSON, or not in the
+/// requested shape, is sent back to the model with the parse error, up to
+/// `params.max_repairs` times.
+async fn parse_with_repair(
+    backend: &dyn LlmBackend,
+    params: &GenerationParams,
+    request: &CreateChatCompletionRequest,
+    mut content: String,
+) -> Result<Vec<ReplyPair>> {
+    let mut repairs = 0;
+    loop {
+        let e = match parse_reply(&content) {
+            Err(e @ (Error::MalformedJson { .. } | Error::SchemaMismatch(_)))
+                if repairs < params.max_repairs =>
+            {
+                e
+            }
+            res => return res,
+        };
+        repairs += 1;
+        log::warn!(
+            "Asking for a corrected reply ({} of {}): {}",
+            repairs,
+            params.max_repairs,
+            e
+        );
+
+        let mut repair = request.clone();
+        repair.messages.push(
+            ChatCompletionRequestAssistantMessageArgs::default()
+                .content(content)
+                .build()?
+                .into(),
+        );
+        repair.messages.push(
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(repair_prompt(&e))
+                .build()?
+                .into(),
+        );
+        let chat = backend.chat(&repair).await?;
+        content = chat
+            .choices
+            .into_iter()
+            .next()
+            .and_then(|choice| choice.message.content)
+            .unwrap_or_default();
+    }
+}
+
+/// Splits a chunk whose reply could not fit in `max_tokens` and generates
+/// each half on its own.
+async fn resplit(
+    backend: &dyn LlmBackend,
+    chunk: &Chunk,
+    params: &GenerationParams,
+    depth: u32,
+) -> Result<Vec<ReplyPair>> {
+    let target_tokens = estimate_tokens(&chunk.text) / 2 + 1;
+    let pieces = pack_by_tokens(std::slice::from_ref(&chunk.text), target_tokens, 0);
+    if depth >= MAX_RESPLIT_DEPTH || pieces.len() < 2 {
+        return Err(Error::TruncatedOutput);
+    }
+    log::info!("Retrying the chunk as {} smaller pieces.", pieces.len());
+
+    let mut pairs = Vec::new();
+    for text in pieces {
+        let piece = Chunk {
+            text,
+            headings: chunk.headings.clone(),
+        };
+        pairs.extend(Box::pin(generate(backend, &piece, params, depth + 1)).await?);
+    }
+    Ok(pairs)
+}
+
+/// Version of the built-in prompts, recorded on every pair. Bump it whenever
+/// the prompt text changes.
+pub const PROMPT_VERSION: &str = "2";
+
+fn system_prompt() -> String {
+    env::var("SYS_PROMPT").unwrap_or(
+        "As a highly skilled assistant, you are tasked with generating informative question and answer pairs from the provided text. Focus on crafting Q&A pairs that are relevant to the primary subject matter of the text. Your questions should be engaging and answers concise, avoiding details of specific examples that are not representative of the text's broader themes. Aim for a comprehensive understanding that captures the essence of the content without being sidetracked by less relevant details."
+    .into())
+}
+
+/// `PROMPT_VERSION`, marked with a hash of the system prompt when
+/// `SYS_PROMPT` overrides it.
+fn prompt_version() -> String {
+    match env::var("SYS_PROMPT") {
+        Ok(sys_prompt) => format!("{}+sys-{}", PROMPT_VERSION, &content_hash(&sys_prompt)[..8]),
+        Err(_) => PROMPT_VERSION.to_string(),
+    }
+}
+
+/// Builds the generation request for `chunk`. `covered` holds pairs already
+/// salvaged from a reply that was cut off, which the model is asked not to
+/// repeat.
+fn build_request(
+    chunk: &Chunk,
+    params: &GenerationParams,
+    covered: &[ReplyPair],
+) -> Result<CreateChatCompletionRequest> {
+    let sys_prompt = system_prompt();
+
+    let section = match chunk.breadcrumb() {
+        Some(path) => format!(" It comes from the section \"{}\".", path),
+        None => String::new(),
+    };
+
+    let mut user_input = format!("
+    Here is the user input to work with.{}
+    ---
+    {}
+    ---
+    Your task is to dissect this text for its central themes and most significant details, crafting question and answer pairs that reflect the core message and primary content. Avoid questions about specific examples that do not contribute to the overall understanding of the subject. The questions should cover different types: factual, inferential, thematic, etc., and answers must be concise and pertinent to the text's main intent. Please generate as many relevant question and answers as possible, focusing on the significance and relevance of each to the text's main topic. Provide the results in the following JSON format:
+    {{
+        \"qa_pairs\": [
+            {{
+                \"question\": \"<Your question>\",
+                \"answer\": \"<Your answer>\",
+                \"type\": \"<factual, inferential, thematic, ...>\",
+                \"difficulty\": \"<easy, medium or hard>\"
+            }},
+            // ... additional Q&A pairs based on text relevance
+        ]
+    }}",
+        section, chunk.text
+    );
+    if !covered.is_empty() {
+        // The previous reply was cut off; ask only for what it did not reach.
+        user_input.push_str("\n    These questions have already been generated. Do not repeat them; generate only additional pairs in the same JSON format:\n");
+        for pair in covered {
+            user_input.push_str(&format!("    - {}\n", pair.question));
+        }
+    }
+
+    let messages = vec![
+        ChatCompletionRequestSystemMessageArgs::default()
+            .content(&sys_prompt)
+            .build()?
+            .into(),
+        ChatCompletionRequestUserMessageArgs::default()
+            .content(user_input)
+            .build()?
+            .into(),
+    ];
+
+    let response_format = ChatCompletionResponseFormat {
+        r#type: ChatCompletionResponseFormatType::JsonObject,
+    };
+
+    let mut request = CreateChatCompletionRequestArgs::default();
+    params.apply(&mut request);
+    Ok(request
+        .messages(messages)
+        .response_format(response_format)
+        .build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::testing::{reply, Scripted};
+    use crate::llm::LlmError;
+    use serde_json::Value;
+
+    fn pairs_json(questions: &[&str]) -> String {
+        let pairs: Vec<Value> = questions
+            .iter()
+            .map(|q| serde_json::json!({ "question": q, "answer": format!("answer to {}", q) }))
+            .collect();
+        serde_json::json!({ "qa_pairs": pairs }).to_string()
+    }
+
+    fn questions(pairs: &[QaPair]) -> Vec<&str> {
+        pairs.iter().map(|pair| pair.question.as_str()).collect()
+    }
+
+    fn chunk() -> Chunk {
+        Chunk::new("Ownership is a set of rules. Borrowing lends a value. Lifetimes bound references. Slices view a collection.")
+    }
+
+    #[tokio::test]
+    async fn continues_a_reply_cut_off_at_max_tokens() {
+        let backend = Scripted::new(vec![
+            reply(
+                r#"{"qa_pairs":[{"question":"What is ownership?","answer":"Rules."},{"question":"What is borr"#,
+                "length",
+            ),
+            reply(&pairs_json(&["What is borrowing?"]), "stop"),
+        ]);
+        let pairs = generate_pairs(&backend, &chunk(), &GenerationParams::default())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            questions(&pairs),
+            vec!["What is ownership?", "What is borrowing?"]
+        );
+        let requests = backend.requests();
+        assert_eq!(requests.len(), 2);
+        // The continuation lists what is already covered.
+        assert!(!requests[0].contains("already been generated"));
+        assert!(requests[1].contains("already been generated"));
+        assert!(requests[1].contains("What is ownership?"));
+    }
+
+    #[tokio::test]
+    async fn stops_continuing_after_max_continuations() {
+        let cut_off = r#"{"qa_pairs":[{"question":"Q","answer":"A"},{"quest"#;
+        let backend = Scripted::new(vec![reply(cut_off, "length"), reply(cut_off, "length")]);
+        let params = GenerationParams {
+            max_continuations: 1,
+            ..GenerationParams::default()
+        };
+        let pairs = generate_pairs(&backend, &chunk(), &params).await.unwrap();
+
+        assert_eq!(pairs.len(), 2);
+        assert_eq!(backend.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn resplits_a_chunk_when_nothing_could_be_salvaged() {
+        let backend = Scripted::new(vec![
+            reply(r#"{"qa_pairs":[{"question":"What is"#, "length"),
+            reply(&pairs_json(&["First half?"]), "stop"),
+            reply(&pairs_json(&["Second half?"]), "stop"),
+        ]);
+        let chunk = chunk();
+        let pairs = generate_pairs(&backend, &chunk, &GenerationParams::default())
+            .await
+            .unwrap();
+
+        assert_eq!(questions(&pairs), vec!["First half?", "Second half?"]);
+        let requests = backend.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[1].contains("Ownership is a set of rules."));
+        assert!(!requests[1].contains("Slices view a collection."));
+        assert!(requests[2].contains("Slices view a collection."));
+        // Pairs from the pieces still point at the whole chunk.
+        assert!(pairs
+            .iter()
+            .all(|pair| pair.chunk_hash == content_hash(&chunk.text)));
+    }
+
+    #[tokio::test]
+    async fn asks_the_model_to_repair_an_unparseable_reply() {
+        let backend = Scripted::new(vec![
+            reply("Sure! Here are your pairs: question one...", "stop"),
+            reply(&pairs_json(&["Repaired?"]), "stop"),
+        ]);
+        let pairs = generate_pairs(&backend, &chunk(), &GenerationParams::default())
+            .await
+            .unwrap();
+
+        assert_eq!(questions(&pairs), vec!["Repaired?"]);
+        let requests = backend.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].contains("could not be used"));
+        assert!(requests[1].contains("Sure! Here are your pairs"));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_repairs() {
+        let backend = Scripted::new(vec![
+            reply("not json", "stop"),
+            reply(r#"{"qa_pairs":[{"question":"No answer"}]}"#, "stop"),
+        ]);
+        let params = GenerationParams {
+            max_repairs: 1,
+            ..GenerationParams::default()
+        };
+        let err = generate_pairs(&backend, &chunk(), &params)
+            .await
+            .unwrap_err();
+
+        assert_eq!(err.kind(), "schema_mismatch");
+        assert_eq!(backend.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn credential_errors_abort_without_further_requests() {
+        let backend = Scripted::new(vec![Err(LlmError::from_status(
+            401,
+            String::from("invalid api key"),
+            None,
+        ))]);
+        let err = generate_pairs(&backend, &chunk(), &GenerationParams::default())
+            .await
+            .unwrap_err();
+
+        assert!(err.aborts_run());
+        assert_eq!(backend.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn other_api_errors_fail_only_the_chunk() {
+        let backend = Scripted::new(vec![Err(LlmError::from_status(
+            400,
+            String::from("bad request"),
+            None,
+        ))]);
+        let err = generate_pairs(&backend, &chunk(), &GenerationParams::default())
+            .await
+            .unwrap_err();
+
+        assert_eq!(err.kind(), "api");
+        assert!(!err.aborts_run());
+    }
+}
@@ -0,0 +1,223 @@
+use crate::chunk::ChunkStrategy;
+use crate::corpus::{get_corpus, DEFAULT_CORPUS};
+use crate::params::GenerationParams;
+use serde::{Deserialize, Serialize};
+use std::env;
+
+/// Where generated pairs are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Sink {
+    #[default]
+    Airtable,
+    /// Append to a CSV file.
+    Csv,
+    /// Append to a JSONL file in the OpenAI chat fine-tuning format.
+    Finetune,
+    /// Generate and log only, useful for trying out prompts and models.
+    None,
+}
+
+impl Sink {
+    /// Reads `SINK`, or `None` when it is not set.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let Ok(sink) = env::var("SINK") else {
+            return Ok(None);
+        };
+        serde_json::from_value(serde_json::Value::String(sink.clone()))
+            .map(Some)
+            .map_err(|_| {
+                anyhow::anyhow!(
+                    "invalid SINK '{}', expected airtable, csv, finetune or none",
+                    sink
+                )
+            })
+    }
+}
+
+/// Payload scheduled by `on_deploy` and parsed by the schedule handler, so
+/// one deployment can run differently-configured jobs.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobPayload {
+    #[serde(default = "default_corpus")]
+    pub corpus: String,
+    /// Index of the first chunk to process.
+    #[serde(default)]
+    pub start: usize,
+    /// Index one past the last chunk to process; `None` runs to the end.
+    #[serde(default)]
+    pub end: Option<usize>,
+    /// Model and sampling parameters, given at the top level of the payload.
+    #[serde(flatten)]
+    pub params: GenerationParams,
+    #[serde(default)]
+    pub sink: Sink,
+    /// Discards saved progress and starts the range over.
+    #[serde(default)]
+    pub restart: bool,
+    /// How the corpus is cut into chunks; chunk indices refer to the result.
+    #[serde(default)]
+    pub chunking: ChunkStrategy,
+    /// Processes the range a slice at a time, one slice per cron tick.
+    #[serde(default)]
+    pub drip: Option<Drip>,
+}
+
+/// Bounds on how much one drip-mode invocation processes before it schedules
+/// the next tick. A tick stops at whichever limit is reached first.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Drip {
+    #[serde(default)]
+    pub max_chunks: Option<usize>,
+    #[serde(default)]
+    pub time_budget_secs: Option<u64>,
+}
+
+fn default_corpus() -> String {
+    DEFAULT_CORPUS.to_string()
+}
+
+impl Default for JobPayload {
+    fn default() -> Self {
+        JobPayload {
+            corpus: default_corpus(),
+            start: 0,
+            end: None,
+            params: GenerationParams::default(),
+            sink: Sink::default(),
+            restart: false,
+            chunking: ChunkStrategy::default(),
+            drip: None,
+        }
+    }
+}
+
+impl JobPayload {
+    /// Builds a payload from `CORPUS`, `CHUNK_START`, `CHUNK_END`, `SINK`,
+    /// `RESTART`, `DRIP_CHUNKS`, `DRIP_SECONDS` and the variables read by
+    /// `GenerationParams::from_env` and `ChunkStrategy::from_env`, leaving
+    /// defaults for anything unset.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut payload = JobPayload::default();
+        if let Ok(corpus) = env::var("CORPUS") {
+            payload.corpus = corpus;
+        }
+        if let Ok(start) = env::var("CHUNK_START") {
+            payload.start = start
+                .parse()
+                .map_err(|e| anyhow::anyhow!("invalid CHUNK_START '{}': {}", start, e))?;
+        }
+        if let Ok(end) = env::var("CHUNK_END") {
+            payload.end = Some(
+                end.parse()
+                    .map_err(|e| anyhow::anyhow!("invalid CHUNK_END '{}': {}", end, e))?,
+            );
+        }
+        payload.params = GenerationParams::from_env()?;
+        if let Some(sink) = Sink::from_env()? {
+            payload.sink = sink;
+        }
+        if let Ok(restart) = env::var("RESTART") {
+            payload.restart = matches!(restart.as_str(), "1" | "true" | "yes");
+        }
+        payload.chunking = ChunkStrategy::from_env()?;
+        let max_chunks = match env::var("DRIP_CHUNKS") {
+            Ok(n) => Some(
+                n.parse()
+                    .map_err(|e| anyhow::anyhow!("invalid DRIP_CHUNKS '{}': {}", n, e))?,
+            ),
+            Err(_) => None,
+        };
+        let time_budget_secs = match env::var("DRIP_SECONDS") {
+            Ok(n) => Some(
+                n.parse()
+                    .map_err(|e| anyhow::anyhow!("invalid DRIP_SECONDS '{}': {}", n, e))?,
+            ),
+            Err(_) => None,
+        };
+        if max_chunks.is_some() || time_budget_secs.is_some() {
+            payload.drip = Some(Drip {
+                max_chunks,
+                time_budget_secs,
+            });
+        }
+        Ok(payload)
+    }
+
+    /// Parses the body delivered to the schedule handler. Jobs scheduled
+    /// before the payload was structured carry either a bare corpus name or
+    /// the literal "cron_job_evoked"; those fall back to `from_env`.
+    pub fn from_body(body: &[u8]) -> anyhow::Result<Self> {
+        let text = String::from_utf8_lossy(body);
+        let text = text.trim();
+        if text.starts_with('{') {
+            return serde_json::from_str(text)
+                .map_err(|e| anyhow::anyhow!("failed to parse job payload: {}", e));
+        }
+
+        let mut payload = JobPayload::from_env()?;
+        if !text.is_empty() && text != "cron_job_evoked" {
+            payload.corpus = text.to_string();
+        }
+        Ok(payload)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        get_corpus(&self.corpus)?;
+        self.params.validate()?;
+        if let Some(end) = self.end {
+            if end < self.start {
+                anyhow::bail!(
+                    "job payload chunk range is empty: start {} is past end {}",
+                    self.start,
+                    end
+                );
+            }
+        }
+        self.chunking.validate()?;
+        if let Some(drip) = &self.drip {
+            if drip.max_chunks == Some(0) || drip.time_budget_secs == Some(0) {
+                anyhow::bail!("drip limits must be greater than zero");
+            }
+            if drip.max_chunks.is_none() && drip.time_budget_secs.is_none() {
+                anyhow::bail!("drip mode needs max_chunks, time_budget_secs or both");
+            }
+        }
+        Ok(())
+    }
+
+    /// Payload for the tick that continues this job. Progress already
+    /// carries what was done, so the next tick must not discard it.
+    pub fn next_tick(&self) -> Self {
+        JobPayload {
+            restart: false,
+            ..self.clone()
+        }
+    }
+
+    /// Key under which this job's progress is saved. Runs of the same corpus
+    /// with a different model or chunking are tracked separately, since
+    /// chunk indices only mean something for one chunking.
+    pub fn state_key(&self) -> String {
+        match &self.chunking {
+            ChunkStrategy::BlankLine => format!("progress:{}:{}", self.corpus, self.params.model),
+            ChunkStrategy::TokenBudget {
+                target_tokens,
+                overlap_tokens,
+            } => format!(
+                "progress:{}:{}:tokens-{}-{}",
+                self.corpus, self.params.model, target_tokens, overlap_tokens
+            ),
+            ChunkStrategy::Markdown { target_tokens } => format!(
+                "progress:{}:{}:markdown-{}",
+                self.corpus, self.params.model, target_tokens
+            ),
+        }
+    }
+
+    /// Clamps the requested chunk range to a corpus of `len` chunks.
+    pub fn chunk_range(&self, len: usize) -> std::ops::Range<usize> {
+        let end = self.end.unwrap_or(len).min(len);
+        self.start.min(end)..end
+    }
+}
@@ -1,195 +1,510 @@
-use airtable_flows::create_record;
-use async_openai::{
-    types::{
-        ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
-        ChatCompletionResponseFormat, ChatCompletionResponseFormatType,
-        CreateChatCompletionRequestArgs,
-    },
-    Client,
-};
-use chrono::prelude::*;
 use dotenv::dotenv;
 use flowsnet_platform_sdk::logger;
 use schedule_flows::{schedule_cron_job, schedule_handler};
-use serde_json;
-use std::collections::HashMap;
-use std::env;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+use webhook_flows::{create_endpoint, request_handler, send_response};
+
+pub mod airtable;
+pub mod chunk;
+pub mod corpus;
+pub mod csv;
+pub mod error;
+pub mod finetune;
+pub mod generation;
+pub mod job;
+pub mod llm;
+pub mod markdown;
+pub mod pair;
+pub mod params;
+pub mod reply;
+pub mod retry;
+pub mod schedule;
+pub mod sink;
+pub mod state;
+pub mod webhook;
+
+use airtable::AirtableConfig;
+pub use chunk::{load_chunks, split_text_into_chunks, Chunk};
+use corpus::get_corpus;
+pub use error::{Error, Result};
+pub use generation::{generate_pairs, PROMPT_VERSION};
+use job::{JobPayload, Sink};
+use llm::{LlmBackend, OpenAiCompatible};
+pub use pair::QaPair;
+use params::GenerationParams;
+use retry::{RetryPolicy, Retrying};
+use schedule::ScheduleConfig;
+pub use sink::upload_airtable;
+use sink::{sink_from_env, PairSink, WriteReport};
+use state::{state_store_from_env, Progress};
+use webhook::OutputFormat;
 
 #[no_mangle]
 #[tokio::main(flavor = "current_thread")]
 pub async fn on_deploy() {
-    let cron_time_with_date = get_cron_time_with_date();
-    schedule_cron_job(cron_time_with_date, String::from("cron_job_evoked")).await;
+    dotenv().ok();
+    logger::init();
+    create_endpoint().await;
+
+    let payload = match JobPayload::from_env().and_then(|p| p.validate().map(|_| p)) {
+        Ok(payload) => payload,
+        Err(e) => {
+            log::error!("Invalid job configuration: {}", e);
+            return;
+        }
+    };
+    if payload.sink == Sink::Airtable {
+        if let Err(e) = AirtableConfig::from_env().and_then(|c| c.check_key_column()) {
+            log::error!("Invalid Airtable configuration: {}", e);
+            return;
+        }
+    }
+    let schedule = match ScheduleConfig::from_env() {
+        Ok(schedule) => schedule,
+        Err(e) => {
+            log::error!("Invalid schedule configuration: {}", e);
+            return;
+        }
+    };
+    let cron = match schedule.initial() {
+        Ok(cron) => cron,
+        Err(e) => {
+            log::error!("Invalid schedule configuration: {}", e);
+            return;
+        }
+    };
+
+    // A recurring job re-sends the same payload on every run, so clear
+    // progress here once rather than on every run.
+    if payload.restart {
+        if let Err(e) = state_store_from_env().clear(&payload.state_key()) {
+            log::error!("Failed to clear saved progress: {}", e);
+        }
+    }
+    let payload =
+        serde_json::to_string(&payload.next_tick()).expect("failed to serialize job payload");
+    log::info!("Job scheduled with cron '{}'.", cron);
+    schedule_cron_job(cron, payload).await;
 }
 
 #[schedule_handler]
 async fn handler(body: Vec<u8>) {
     dotenv().ok();
     logger::init();
-    let json_contents = include_str!("../rust_chapter.json");
 
-    let data: Vec<String> = serde_json::from_str(json_contents).expect("failed to parse json");
-    let mut count = 0;
+    let payload = match JobPayload::from_body(&body).and_then(|p| p.validate().map(|_| p)) {
+        Ok(payload) => payload,
+        Err(e) => {
+            log::error!("Invalid job payload: {}", e);
+            return;
+        }
+    };
+    let mut writer = match sink_from_env(payload.sink) {
+        Ok(writer) => writer,
+        Err(e) => {
+            log::error!("Invalid sink configuration: {}", e);
+            return;
+        }
+    };
+
+    let data = match get_corpus(&payload.corpus).map(|corpus| corpus.chunks(&payload.chunking)) {
+        Ok(data) => data,
+        Err(e) => {
+            log::error!("Failed to load corpus: {}", e);
+            return;
+        }
+    };
+    let range = payload.chunk_range(data.len());
+    log::info!(
+        "Processing chunks {}..{} of corpus '{}' with {}.",
+        range.start,
+        range.end,
+        payload.corpus,
+        payload.params.model
+    );
+    let backend = match backend_from_env() {
+        Ok(backend) => backend,
+        Err(e) => {
+            log::error!("Invalid LLM backend configuration: {}", e);
+            return;
+        }
+    };
+    let store = state_store_from_env();
+    let state_key = payload.state_key();
+    if payload.restart {
+        if let Err(e) = store.clear(&state_key) {
+            log::error!("Failed to clear saved progress: {}", e);
+        }
+    }
+    let mut progress = match store.load(&state_key) {
+        Ok(progress) => progress.unwrap_or_default(),
+        Err(e) => {
+            log::error!("Failed to load saved progress, starting over: {}", e);
+            Progress::default()
+        }
+    };
+
+    // In drip mode failed chunks are skipped too, so a chunk that keeps
+    // failing cannot stall every tick; re-trigger without drip to retry them.
+    let started = Instant::now();
+    let mut processed = 0;
+    let mut unfinished = false;
     let mut chunk_count = 0;
-    let chunks_len = data.len();
-    for user_input in data {
+    let mut run = RunReport::default();
+    let chunks_len = range.len();
+    for index in range {
         chunk_count += 1;
-        match gen_pair(&user_input).await {
-            Ok(Some(qa_pairs)) => {
-                for _ in qa_pairs {
-                    count += 1;
-                }
+        if progress.should_skip(index, payload.drip.is_some()) {
+            log::info!("Skipping chunk {}, already processed.", index);
+            continue;
+        }
+        if let Some(drip) = &payload.drip {
+            let chunks_spent = drip.max_chunks.is_some_and(|max| processed >= max);
+            let time_spent = drip
+                .time_budget_secs
+                .is_some_and(|secs| started.elapsed() >= Duration::from_secs(secs));
+            if chunks_spent || time_spent {
+                unfinished = true;
+                break;
             }
-            Ok(None) => {
+        }
+        processed += 1;
+        let chunk = &data[index];
+        let corpus = Some(payload.corpus.as_str());
+        match process_chunk(
+            &backend,
+            writer.as_mut(),
+            chunk,
+            &payload.params,
+            corpus,
+            index,
+        )
+        .await
+        {
+            Ok((qa_pairs, _)) if qa_pairs.is_empty() => {
                 log::warn!("No Q&A pairs generated for the current chunk.");
+                progress.mark_failed(index, String::from("no Q&A pairs generated"));
+            }
+            Ok((qa_pairs, report)) => {
+                // Pairs that could not be written are in the dead-letter
+                // file; regenerating the chunk would duplicate the rest.
+                run.add(qa_pairs.len(), report);
+                progress.mark_done(index, qa_pairs.len());
+            }
+            Err(e) if e.aborts_run() => {
+                // Every remaining chunk would fail the same way. The chunk is
+                // left unmarked so it is retried once credentials are fixed.
+                log::error!("Aborting the run on a credential error: {}", e);
+                shut_down(writer.as_mut()).await;
+                return;
             }
             Err(e) => {
-                log::error!("Failed to generate Q&A pairs: {:?}", e);
+                run.fail(&e);
+                progress.mark_failed(index, format!("{}: {}", e.kind(), e));
             }
         }
+        if let Err(e) = store.save(&state_key, &progress) {
+            log::error!("Failed to save progress: {}", e);
+        }
         log::info!(
             "Processed {} Q&A pairs in {} of {} sections.",
-            count,
+            run.pairs,
             chunk_count,
             chunks_len
         );
     }
+    shut_down(writer.as_mut()).await;
+    run.log();
+
+    if unfinished {
+        match ScheduleConfig::from_env() {
+            Ok(schedule) if schedule.every_minutes.is_some() => {
+                log::info!("Tick budget spent, the next recurring run continues the job.");
+            }
+            Ok(schedule) => {
+                let next = serde_json::to_string(&payload.next_tick())
+                    .expect("failed to serialize job payload");
+                let cron = schedule.one_shot();
+                log::info!(
+                    "Tick budget spent, next tick scheduled with cron '{}'.",
+                    cron
+                );
+                schedule_cron_job(cron, next).await;
+            }
+            Err(e) => {
+                log::error!("Failed to schedule the next tick: {}", e);
+            }
+        }
+    } else if payload.drip.is_some() {
+        log::info!("Drip job for corpus '{}' is complete.", payload.corpus);
+    }
 }
 
-pub async fn gen_pair(
-    user_input: &str,
-) -> Result<Option<Vec<(String, String)>>, Box<dyn std::error::Error>> {
-    let sys_prompt = env::var("SYS_PROMPT").unwrap_or(
-        "As a highly skilled assistant, you are tasked with generating informative question and answer pairs from the provided text. Focus on crafting Q&A pairs that are relevant to the primary subject matter of the text. Your questions should be engaging and answers concise, avoiding details of specific examples that are not representative of the text's broader themes. Aim for a comprehensive understanding that captures the essence of the content without being sidetracked by less relevant details."
-    .into());
-
-    let user_input = format!("
-    Here is the user input to work with:
-    ---
-    {}
-    ---
-    Your task is to dissect this text for its central themes and most significant details, crafting question and answer pairs that reflect the core message and primary content. Avoid questions about specific examples that do not contribute to the overall understanding of the subject. The questions should cover different types: factual, inferential, thematic, etc., and answers must be concise and pertinent to the text's main intent. Please generate as many relevant question and answers as possible, focusing on the significance and relevance of each to the text's main topic. Provide the results in the following JSON format:
-    {{
-        \"qa_pairs\": [
-            {{
-                \"question\": \"<Your question>\",
-                \"answer\": \"<Your answer>\"
-            }},
-            // ... additional Q&A pairs based on text relevance
-        ]
-    }}",
-        user_input
-    );
+/// Generates Q&A pairs for a text/plain, markdown or JSON request body and
+/// responds with CSV, or JSONL when the `Accept` header asks for it.
+#[request_handler]
+async fn webhook_handler(
+    headers: Vec<(String, String)>,
+    _subpath: String,
+    _qry: HashMap<String, Value>,
+    body: Vec<u8>,
+) {
+    dotenv().ok();
+    logger::init();
 
-    let messages = vec![
-        ChatCompletionRequestSystemMessageArgs::default()
-            .content(&sys_prompt)
-            .build()
-            .expect("Failed to build system message")
-            .into(),
-        ChatCompletionRequestUserMessageArgs::default()
-            .content(user_input)
-            .build()?
-            .into(),
-    ];
-
-    let client = Client::new();
-
-    let response_format = ChatCompletionResponseFormat {
-        r#type: ChatCompletionResponseFormatType::JsonObject,
+    // The webhook chunks the request body, not CORPUS, and ignores the chunk
+    // range and drip limits, so only the generation settings are checked.
+    let job = match JobPayload::from_env().and_then(|p| {
+        p.params.validate()?;
+        p.chunking.validate()?;
+        Ok(p)
+    }) {
+        Ok(job) => job,
+        Err(e) => {
+            log::error!("Invalid job configuration: {}", e);
+            send_text(500, format!("Invalid job configuration: {}", e));
+            return;
+        }
     };
-
-    let request = CreateChatCompletionRequestArgs::default()
-        .max_tokens(4000u16)
-        .model("gpt-4-1106-preview")
-        // .model("gpt-3.5-turbo-1106")
-        .messages(messages)
-        .response_format(response_format)
-        .build()?;
-
-    let chat = match client.chat().create(request).await {
-        Ok(chat) => chat,
-
+    // The webhook returns its pairs in the response, so it only writes them
+    // elsewhere when SINK asks for it explicitly.
+    let sink = match Sink::from_env() {
+        Ok(Some(_)) => job.sink,
+        _ => Sink::None,
+    };
+    let mut writer = match sink_from_env(sink) {
+        Ok(writer) => writer,
         Err(e) => {
-            log::error!("Failed to create chat: {:?}", e);
-            return Ok(None);
+            log::error!("Invalid sink configuration: {}", e);
+            send_text(500, format!("Invalid sink configuration: {}", e));
+            return;
+        }
+    };
+    let backend = match backend_from_env() {
+        Ok(backend) => backend,
+        Err(e) => {
+            log::error!("Invalid LLM backend configuration: {}", e);
+            send_text(500, format!("Invalid LLM backend configuration: {}", e));
+            return;
+        }
+    };
+    let chunks = match webhook::parse_body(
+        webhook::header(&headers, "content-type"),
+        &body,
+        &job.chunking,
+    ) {
+        Ok(chunks) => chunks,
+        Err(e) => {
+            send_text(400, format!("Invalid request body: {}", e));
+            return;
         }
     };
 
-    #[derive(serde::Deserialize)]
-    struct QaPair {
-        question: String,
-        answer: String,
-    }
-
-    let mut qa_pairs_vec = Vec::new();
-    if let Some(qa_pairs_json) = &chat.choices[0].message.content {
-        let deserialized: HashMap<String, Vec<QaPair>> = match serde_json::from_str(&qa_pairs_json)
-        {
-            Ok(deserialized) => deserialized,
-            Err(e) => {
-                log::error!("Failed to deserialize qa_pairs_json: {:?}", e);
-                return Ok(None);
+    let mut pairs = Vec::new();
+    let mut run = RunReport::default();
+    let chunks_len = chunks.len();
+    for (index, chunk) in chunks.iter().enumerate() {
+        match process_chunk(&backend, writer.as_mut(), chunk, &job.params, None, index).await {
+            Ok((qa_pairs, _)) if qa_pairs.is_empty() => {
+                log::warn!("No Q&A pairs generated for the current chunk.");
             }
-        };
-
-        if let Some(qa_pairs) = deserialized.get("qa_pairs") {
-            qa_pairs_vec = qa_pairs
-                .iter()
-                .map(|qa| (qa.question.clone(), qa.answer.clone()))
-                .collect();
+            Ok((qa_pairs, report)) => {
+                run.add(qa_pairs.len(), report);
+                pairs.extend(qa_pairs);
+            }
+            Err(e) if e.aborts_run() => {
+                log::error!("Aborting the request on a credential error: {}", e);
+                shut_down(writer.as_mut()).await;
+                send_text(502, format!("LLM backend rejected our credentials: {}", e));
+                return;
+            }
+            Err(e) => run.fail(&e),
         }
+        log::info!(
+            "Processed {} Q&A pairs in {} of {} sections.",
+            run.pairs,
+            index + 1,
+            chunks_len
+        );
+    }
+    shut_down(writer.as_mut()).await;
+    run.log();
+
+    let failed = run.failed_chunks();
+    if pairs.is_empty() && failed > 0 {
+        send_text(
+            502,
+            format!(
+                "Failed to generate Q&A pairs for {} of {} chunks: {}.",
+                failed,
+                chunks_len,
+                run.failure_summary()
+            ),
+        );
+        return;
     }
-    for (question, answer) in &qa_pairs_vec {
-        upload_airtable(question, answer).await;
+    let format = OutputFormat::from_accept(webhook::header(&headers, "accept"));
+    send_response(
+        200,
+        vec![
+            (
+                String::from("content-type"),
+                String::from(format.content_type()),
+            ),
+            (String::from("x-failed-chunks"), failed.to_string()),
+        ],
+        webhook::render(&pairs, format).into_bytes(),
+    );
+}
+
+/// Generates the pairs for the chunk at `index`, records where it came from
+/// and writes the pairs out.
+async fn process_chunk(
+    backend: &dyn LlmBackend,
+    writer: &mut dyn PairSink,
+    chunk: &Chunk,
+    params: &GenerationParams,
+    corpus: Option<&str>,
+    index: usize,
+) -> Result<(Vec<QaPair>, WriteReport)> {
+    let qa_pairs: Vec<QaPair> = generate_pairs(backend, chunk, params)
+        .await?
+        .into_iter()
+        .map(|pair| pair.located(corpus, index))
+        .collect();
+    let report = write_chunk(writer, &qa_pairs).await?;
+    if report.failed > 0 {
+        log::warn!(
+            "{} of {} Q&A pairs for chunk {} could not be written.",
+            report.failed,
+            qa_pairs.len(),
+            index
+        );
     }
+    Ok((qa_pairs, report))
+}
 
-    Ok(Some(qa_pairs_vec))
+/// Tallies of a run or webhook request, logged when it ends.
+#[derive(Debug, Default)]
+struct RunReport {
+    pairs: usize,
+    /// Failed chunks by error kind.
+    failures: BTreeMap<&'static str, usize>,
+    writes: WriteReport,
 }
 
-pub fn split_text_into_chunks(raw_text: &str) -> Vec<String> {
-    let mut res = Vec::new();
-    let mut current_section = String::new();
+impl RunReport {
+    fn add(&mut self, pairs: usize, report: WriteReport) {
+        self.pairs += pairs;
+        self.writes.add(report);
+    }
+
+    fn fail(&mut self, e: &Error) {
+        log::error!("Failed to generate Q&A pairs ({}): {}", e.kind(), e);
+        *self.failures.entry(e.kind()).or_insert(0) += 1;
+    }
+
+    fn failed_chunks(&self) -> usize {
+        self.failures.values().sum()
+    }
 
-    for line in raw_text.lines() {
-        if !line.trim().is_empty() {
-            current_section.push_str(line);
-            current_section.push('\n');
+    /// Failure counts by kind, e.g. "2 rate_limit, 1 api".
+    fn failure_summary(&self) -> String {
+        self.failures
+            .iter()
+            .map(|(kind, n)| format!("{} {}", n, kind))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    fn log(&self) {
+        if !self.failures.is_empty() {
+            log::warn!("Failed chunks this run: {}.", self.failure_summary());
+        }
+        if self.writes.failed > 0 {
+            log::warn!(
+                "{} Q&A pairs could not be written this run, see the dead-letter file.",
+                self.writes.failed
+            );
+        }
+        if self.writes.unchecked > 0 {
+            log::warn!(
+                "{} Q&A pairs were sent without confirmation that they were written; set airtable_api_key to check writes.",
+                self.writes.unchecked
+            );
         }
+    }
+}
+
+/// Writes one chunk's pairs and flushes them, so that a chunk is only
+/// recorded as done once its pairs are out.
+async fn write_chunk(writer: &mut dyn PairSink, pairs: &[QaPair]) -> Result<WriteReport> {
+    let mut report = writer.write(pairs).await?;
+    report.add(writer.flush().await?);
+    Ok(report)
+}
 
-        if line.trim().is_empty() && !current_section.trim().is_empty() {
-            res.push(current_section.clone());
-            current_section.clear();
+/// Flushes anything still queued before the run ends.
+async fn shut_down(writer: &mut dyn PairSink) {
+    match writer.flush().await {
+        Ok(report) if report.failed > 0 => {
+            log::warn!("{} queued Q&A pairs could not be written.", report.failed)
         }
+        Ok(_) => {}
+        Err(e) => log::error!("Failed to flush queued Q&A pairs: {}", e),
     }
-    res
 }
 
-pub async fn upload_airtable(question: &str, answer: &str) {
-    let airtable_token_name = env::var("airtable_token_name").unwrap_or("github".to_string());
-    let airtable_base_id = env::var("airtable_base_id").unwrap_or("appmhvMGsMRPmuUWJ".to_string());
-    let airtable_table_name = env::var("airtable_table_name").unwrap_or("mention".to_string());
-
-    let data = serde_json::json!({
-        "Question": question,
-        "Answer": answer,
-    });
-    let _ = create_record(
-        &airtable_token_name,
-        &airtable_base_id,
-        &airtable_table_name,
-        data.clone(),
+fn backend_from_env() -> anyhow::Result<Retrying<OpenAiCompatible>> {
+    Ok(Retrying::new(
+        OpenAiCompatible::from_env()?,
+        RetryPolicy::from_env()?,
+    ))
+}
+
+fn send_text(status: u16, message: String) {
+    send_response(
+        status,
+        vec![(String::from("content-type"), String::from("text/plain"))],
+        message.into_bytes(),
     );
 }
 
-fn get_cron_time_with_date() -> String {
-    let now = Local::now();
-    let now_minute = now.minute() + 2;
-    format!(
-        "{:02} {:02} {:02} {:02} *",
-        now_minute,
-        now.hour(),
-        now.day(),
-        now.month(),
-    )
+/// Generates Q&A pairs for `user_input` with the backend and parameters
+/// configured in the environment, and writes them to the sink `SINK` picks,
+/// Airtable by default. Returns `Ok(None)` when generation fails.
+///
+/// This is the original single-text entry point. It sets up a new sink on
+/// every call; to process many chunks, use `generate_pairs` with one
+/// `sink::PairSink` for the whole run.
+pub async fn gen_pair(
+    user_input: &str,
+) -> std::result::Result<Option<Vec<(String, String)>>, Box<dyn std::error::Error>> {
+    let backend = backend_from_env()?;
+    let params = GenerationParams::from_env()?;
+    let mut writer = sink_from_env(Sink::from_env()?.unwrap_or_default())?;
+
+    let mut run = RunReport::default();
+    let chunk = Chunk::new(user_input);
+    let qa_pairs = match process_chunk(&backend, writer.as_mut(), &chunk, &params, None, 0).await {
+        Ok((qa_pairs, report)) => {
+            run.add(qa_pairs.len(), report);
+            qa_pairs
+        }
+        Err(e) => {
+            run.fail(&e);
+            run.log();
+            return Ok(None);
+        }
+    };
+    shut_down(writer.as_mut()).await;
+    run.log();
+    Ok(Some(
+        qa_pairs
+            .into_iter()
+            .map(|pair| (pair.question, pair.answer))
+            .collect(),
+    ))
 }
@@ -0,0 +1,276 @@
+use crate::retry::parse_retry_after;
+use async_openai::types::{CreateChatCompletionRequest, CreateChatCompletionResponse};
+use async_trait::async_trait;
+use chrono::Utc;
+use http_req::{
+    request::{Method, Request},
+    uri::Uri,
+};
+use std::collections::HashMap;
+use std::env;
+use std::time::Duration;
+
+pub const DEFAULT_API_BASE: &str = "https://api.openai.com/v1";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Why a chat completion failed, classified by whether retrying can help.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LlmError {
+    /// Rate limits, server errors and transport failures such as timeouts.
+    Retryable {
+        status: Option<u16>,
+        message: String,
+        /// How long the server asked us to wait, from `Retry-After`.
+        retry_after: Option<Duration>,
+    },
+    /// Errors that retrying cannot fix, such as bad requests or bad
+    /// credentials.
+    Fatal {
+        status: Option<u16>,
+        message: String,
+    },
+}
+
+impl LlmError {
+    /// Classifies a non-success HTTP status.
+    pub fn from_status(status: u16, message: String, retry_after: Option<Duration>) -> Self {
+        match status {
+            408 | 409 | 429 | 500..=599 => LlmError::Retryable {
+                status: Some(status),
+                message,
+                retry_after,
+            },
+            _ => LlmError::Fatal {
+                status: Some(status),
+                message,
+            },
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, LlmError::Retryable { .. })
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            LlmError::Retryable { retry_after, .. } => *retry_after,
+            LlmError::Fatal { .. } => None,
+        }
+    }
+
+    /// Credential errors fail every request alike, so they should stop the
+    /// whole run rather than each chunk in turn.
+    pub fn is_auth(&self) -> bool {
+        matches!(
+            self,
+            LlmError::Fatal {
+                status: Some(401) | Some(403),
+                ..
+            }
+        )
+    }
+}
+
+impl std::fmt::Display for LlmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LlmError::Retryable {
+                status: Some(status),
+                message,
+                ..
+            }
+            | LlmError::Fatal {
+                status: Some(status),
+                message,
+            } => write!(f, "chat completion returned {}: {}", status, message),
+            LlmError::Retryable { message, .. } | LlmError::Fatal { message, .. } => {
+                write!(f, "chat completion failed: {}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LlmError {}
+
+/// A chat completion service that `generate_pairs` generates pairs through.
+#[async_trait(?Send)]
+pub trait LlmBackend {
+    async fn chat(
+        &self,
+        request: &CreateChatCompletionRequest,
+    ) -> Result<CreateChatCompletionResponse, LlmError>;
+}
+
+/// Any server speaking the OpenAI chat completions API: OpenAI itself, or a
+/// local llama.cpp, vLLM or Ollama server, or a mock server in tests.
+#[derive(Debug, Clone)]
+pub struct OpenAiCompatible {
+    pub api_base: String,
+    /// Sent as a bearer token when set; local servers usually need none.
+    pub api_key: Option<String>,
+    pub headers: Vec<(String, String)>,
+}
+
+impl OpenAiCompatible {
+    pub fn new(api_base: impl Into<String>) -> Self {
+        OpenAiCompatible {
+            api_base: api_base.into(),
+            api_key: None,
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn with_api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Reads `OPENAI_API_BASE`, `OPENAI_API_KEY` and `LLM_HEADERS`, a JSON
+    /// object of extra request headers.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let api_base = env::var("OPENAI_API_BASE").unwrap_or(DEFAULT_API_BASE.to_string());
+        let mut backend = OpenAiCompatible::new(api_base);
+        if let Ok(api_key) = env::var("OPENAI_API_KEY") {
+            backend = backend.with_api_key(api_key);
+        }
+        if let Ok(headers) = env::var("LLM_HEADERS") {
+            let headers: HashMap<String, String> = serde_json::from_str(&headers).map_err(|e| {
+                anyhow::anyhow!("LLM_HEADERS must be a JSON object of strings: {}", e)
+            })?;
+            backend.headers.extend(headers);
+        }
+        Ok(backend)
+    }
+
+    fn url(&self) -> String {
+        format!("{}/chat/completions", self.api_base.trim_end_matches('/'))
+    }
+}
+
+#[async_trait(?Send)]
+impl LlmBackend for OpenAiCompatible {
+    async fn chat(
+        &self,
+        request: &CreateChatCompletionRequest,
+    ) -> Result<CreateChatCompletionResponse, LlmError> {
+        let url = self.url();
+        let uri = Uri::try_from(url.as_str()).map_err(|e| LlmError::Fatal {
+            status: None,
+            message: format!("invalid chat completions URL '{}': {}", url, e),
+        })?;
+        let body = serde_json::to_vec(request).map_err(|e| LlmError::Fatal {
+            status: None,
+            message: format!("failed to serialize request: {}", e),
+        })?;
+        let bearer = self.api_key.as_ref().map(|key| format!("Bearer {}", key));
+
+        let mut writer = Vec::new();
+        let mut req = Request::new(&uri);
+        req.method(Method::POST)
+            .header("Content-Type", "application/json")
+            .header("Content-Length", &body.len())
+            .timeout(Some(REQUEST_TIMEOUT))
+            .body(&body);
+        if let Some(bearer) = &bearer {
+            req.header("Authorization", bearer);
+        }
+        for (name, value) in &self.headers {
+            req.header(name, value);
+        }
+        let res = req.send(&mut writer).map_err(|e| LlmError::Retryable {
+            status: None,
+            message: e.to_string(),
+            retry_after: None,
+        })?;
+
+        if !res.status_code().is_success() {
+            let retry_after = res
+                .headers()
+                .get("Retry-After")
+                .and_then(|v| parse_retry_after(v, Utc::now()));
+            return Err(LlmError::from_status(
+                u16::from(res.status_code()),
+                String::from_utf8_lossy(&writer).into_owned(),
+                retry_after,
+            ));
+        }
+        // A truncated or garbled body is more likely a transport hiccup than
+        // a reply that will never parse.
+        serde_json::from_slice(&writer).map_err(|e| LlmError::Retryable {
+            status: None,
+            message: format!("failed to parse chat completion response: {}", e),
+            retry_after: None,
+        })
+    }
+}
+
+/// A scripted backend for tests of code that talks to the model.
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::{LlmBackend, LlmError};
+    use async_openai::types::{CreateChatCompletionRequest, CreateChatCompletionResponse};
+    use async_trait::async_trait;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    /// Answers each chat request with the next scripted reply and records
+    /// the requests it was sent.
+    pub(crate) struct Scripted {
+        replies: RefCell<VecDeque<std::result::Result<CreateChatCompletionResponse, LlmError>>>,
+        requests: RefCell<Vec<String>>,
+    }
+
+    impl Scripted {
+        pub(crate) fn new(
+            replies: Vec<std::result::Result<CreateChatCompletionResponse, LlmError>>,
+        ) -> Self {
+            Scripted {
+                replies: RefCell::new(replies.into()),
+                requests: RefCell::new(Vec::new()),
+            }
+        }
+
+        pub(crate) fn requests(&self) -> Vec<String> {
+            self.requests.borrow().clone()
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl LlmBackend for Scripted {
+        async fn chat(
+            &self,
+            request: &CreateChatCompletionRequest,
+        ) -> std::result::Result<CreateChatCompletionResponse, LlmError> {
+            self.requests
+                .borrow_mut()
+                .push(serde_json::to_string(request).unwrap());
+            self.replies
+                .borrow_mut()
+                .pop_front()
+                .expect("more requests than scripted replies")
+        }
+    }
+
+    pub(crate) fn reply(
+        content: &str,
+        finish_reason: &str,
+    ) -> std::result::Result<CreateChatCompletionResponse, LlmError> {
+        Ok(serde_json::from_value(serde_json::json!({
+            "id": "chatcmpl-test",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "test-model",
+            "choices": [{
+                "index": 0,
+                "message": { "role": "assistant", "content": content },
+                "finish_reason": finish_reason,
+            }],
+        }))
+        .unwrap())
+    }
+}
@@ -0,0 +1,304 @@
+use crate::chunk::{estimate_tokens, pack_by_tokens, Chunk, CHARS_PER_TOKEN};
+
+/// A run of markdown lines that must stay together in one chunk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Block {
+    Heading {
+        level: usize,
+        title: String,
+        line: String,
+    },
+    /// Fenced code, tables and lists, which are never split.
+    Atomic(String),
+    Paragraph(String),
+}
+
+/// Chunks markdown by its structure. Chunks never span two sections, and
+/// fenced code blocks, tables and lists are never split, even when they are
+/// larger than `target_tokens`. Each chunk carries the path of headings above
+/// it.
+pub fn chunk_markdown(text: &str, target_tokens: usize) -> Vec<Chunk> {
+    let mut res = Vec::new();
+    // Level and title of each heading on the path to the current section.
+    let mut path: Vec<(usize, String)> = Vec::new();
+    let mut headings: Vec<String> = Vec::new();
+    let mut current = Section::default();
+
+    for block in parse_blocks(text) {
+        match block {
+            Block::Heading { level, title, line } => {
+                current.flush(&mut res, &headings);
+                // A heading closes every open section at its level or
+                // deeper, even when levels were skipped on the way down.
+                while path.last().is_some_and(|(open, _)| *open >= level) {
+                    path.pop();
+                }
+                path.push((level, title));
+                headings = path.iter().map(|(_, title)| title.clone()).collect();
+                current.heading = Some(line);
+            }
+            Block::Paragraph(block) if estimate_tokens(&block) > target_tokens => {
+                // Flushing a heading with no content would drop it, so let
+                // the first piece open the section instead.
+                if !current.blocks.is_empty() {
+                    current.flush(&mut res, &headings);
+                }
+                let mut pieces = pack_by_tokens(&[block], target_tokens, 0);
+                let last = pieces.pop();
+                for piece in pieces {
+                    current.blocks.push(piece);
+                    current.flush(&mut res, &headings);
+                }
+                current.blocks.extend(last);
+            }
+            Block::Atomic(block) | Block::Paragraph(block) => {
+                if !current.fits(&block, target_tokens) {
+                    current.flush(&mut res, &headings);
+                }
+                current.blocks.push(block);
+            }
+        }
+    }
+    current.flush(&mut res, &headings);
+    res
+}
+
+/// Blocks gathered for the chunk being built. The section heading is held
+/// separately so that it opens the section's first chunk rather than
+/// becoming a chunk of its own.
+#[derive(Default)]
+struct Section {
+    heading: Option<String>,
+    blocks: Vec<String>,
+}
+
+impl Section {
+    /// Whether `block` can join the chunk without exceeding `target_tokens`.
+    /// A chunk with no content yet always accepts its first block.
+    fn fits(&self, block: &str, target_tokens: usize) -> bool {
+        if self.blocks.is_empty() {
+            return true;
+        }
+        let joined: usize = self
+            .heading
+            .iter()
+            .chain(self.blocks.iter())
+            .map(|b| b.chars().count() + 2)
+            .sum();
+        estimate_tokens(block) + joined.div_ceil(CHARS_PER_TOKEN) <= target_tokens
+    }
+
+    fn flush(&mut self, res: &mut Vec<Chunk>, headings: &[String]) {
+        if self.blocks.is_empty() {
+            // A heading directly followed by another heading has no content
+            // of its own; the breadcrumb still records it.
+            self.heading = None;
+            return;
+        }
+        let text = self
+            .heading
+            .take()
+            .into_iter()
+            .chain(self.blocks.drain(..))
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        res.push(Chunk {
+            text,
+            headings: headings.to_vec(),
+        });
+    }
+}
+
+fn parse_blocks(text: &str) -> Vec<Block> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut blocks = Vec::new();
+    let mut i = 0;
+
+    while i < lines.len() {
+        let line = lines[i];
+        let trimmed = line.trim_start();
+
+        if trimmed.is_empty() {
+            i += 1;
+        } else if let Some(fence) = fence_marker(trimmed) {
+            let start = i;
+            i += 1;
+            while i < lines.len() && !lines[i].trim_start().starts_with(fence) {
+                i += 1;
+            }
+            // Include the closing fence; an unclosed fence runs to the end.
+            i = (i + 1).min(lines.len());
+            blocks.push(Block::Atomic(lines[start..i].join("\n")));
+        } else if let Some((level, title)) = heading(trimmed) {
+            blocks.push(Block::Heading {
+                level,
+                title,
+                line: line.to_string(),
+            });
+            i += 1;
+        } else if trimmed.starts_with('|') {
+            let start = i;
+            while i < lines.len() && lines[i].trim_start().starts_with('|') {
+                i += 1;
+            }
+            blocks.push(Block::Atomic(lines[start..i].join("\n")));
+        } else if is_list_item(trimmed) {
+            let start = i;
+            i += 1;
+            while i < lines.len() {
+                let next = lines[i];
+                if next.trim().is_empty() {
+                    // A blank line only continues the list when more list
+                    // content follows it.
+                    match lines.get(i + 1) {
+                        Some(after)
+                            if is_list_item(after.trim_start())
+                                || after.starts_with(' ')
+                                || after.starts_with('\t') =>
+                        {
+                            i += 1;
+                        }
+                        _ => break,
+                    }
+                } else if is_list_item(next.trim_start())
+                    || next.starts_with(' ')
+                    || next.starts_with('\t')
+                {
+                    i += 1;
+                } else {
+                    break;
+                }
+            }
+            blocks.push(Block::Atomic(lines[start..i].join("\n")));
+        } else {
+            let start = i;
+            while i < lines.len() {
+                let next = lines[i].trim_start();
+                if next.is_empty()
+                    || heading(next).is_some()
+                    || fence_marker(next).is_some()
+                    || next.starts_with('|')
+                    || is_list_item(next)
+                {
+                    break;
+                }
+                i += 1;
+            }
+            blocks.push(Block::Paragraph(lines[start..i].join("\n")));
+        }
+    }
+    blocks
+}
+
+fn fence_marker(line: &str) -> Option<&'static str> {
+    if line.starts_with("```") {
+        Some("```")
+    } else if line.starts_with("~~~") {
+        Some("~~~")
+    } else {
+        None
+    }
+}
+
+fn heading(line: &str) -> Option<(usize, String)> {
+    let level = line.chars().take_while(|c| *c == '#').count();
+    if level == 0 || level > 6 {
+        return None;
+    }
+    let rest = &line[level..];
+    if !rest.is_empty() && !rest.starts_with(' ') {
+        return None;
+    }
+    let title = rest.trim().trim_end_matches('#').trim();
+    Some((level, title.to_string()))
+}
+
+fn is_list_item(line: &str) -> bool {
+    if line.starts_with("- ") || line.starts_with("* ") || line.starts_with("+ ") {
+        return true;
+    }
+    let digits = line.chars().take_while(|c| c.is_ascii_digit()).count();
+    digits > 0 && (line[digits..].starts_with(". ") || line[digits..].starts_with(") "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(chunks: &[Chunk]) -> Vec<&str> {
+        chunks.iter().map(|chunk| chunk.text.as_str()).collect()
+    }
+
+    #[test]
+    fn keeps_fenced_code_with_blank_lines_whole() {
+        let code = "```rust\nfn main() {\n\n    println!(\"hi\");\n\n}\n```";
+        let text = format!("# Code\n\nIntro paragraph.\n\n{}\n\nAfter.", code);
+        let chunks = chunk_markdown(&text, 8);
+        assert!(
+            chunks.iter().any(|chunk| chunk.text == code),
+            "{:?}",
+            chunks
+        );
+        assert!(chunks
+            .iter()
+            .all(|chunk| chunk.text == code || !chunk.text.contains("```")));
+    }
+
+    #[test]
+    fn keeps_tables_whole() {
+        let table = "| Kind | Use |\n| --- | --- |\n| Pod | Runs containers |\n| Service | Routes traffic |";
+        let text = format!("Before the table.\n\n{}\n\nAfter the table.", table);
+        let chunks = chunk_markdown(&text, 5);
+        assert_eq!(
+            texts(&chunks),
+            vec!["Before the table.", table, "After the table."]
+        );
+    }
+
+    #[test]
+    fn keeps_list_items_with_their_continuation_paragraphs() {
+        let list = "- First item\n\n  More about the first item.\n- Second item";
+        let text = format!("{}\n\nA paragraph after the list.", list);
+        let chunks = chunk_markdown(&text, 8);
+        assert_eq!(texts(&chunks), vec![list, "A paragraph after the list."]);
+    }
+
+    #[test]
+    fn tracks_the_heading_path_across_skipped_levels() {
+        let text =
+            "# Book\n\nPreface.\n\n### Deep\n\nOne.\n\n### Deeper\n\nTwo.\n\n## Chapter\n\nThree.";
+        let chunks = chunk_markdown(text, 100);
+        let paths: Vec<Option<String>> = chunks.iter().map(Chunk::breadcrumb).collect();
+        assert_eq!(
+            paths,
+            vec![
+                Some(String::from("Book")),
+                Some(String::from("Book > Deep")),
+                Some(String::from("Book > Deeper")),
+                Some(String::from("Book > Chapter")),
+            ]
+        );
+        assert_eq!(chunks[3].text, "## Chapter\n\nThree.");
+    }
+
+    #[test]
+    fn splits_an_oversized_paragraph_under_its_heading() {
+        let paragraph =
+            "Pods run containers. Services route traffic. Volumes hold data. Nodes host pods.";
+        let text = format!("## Basics\n\n{}", paragraph);
+        let chunks = chunk_markdown(&text, 10);
+        assert!(chunks.len() > 1);
+        assert!(chunks[0]
+            .text
+            .starts_with("## Basics\n\nPods run containers."));
+        for chunk in &chunks {
+            assert_eq!(chunk.breadcrumb().as_deref(), Some("Basics"));
+        }
+        let rejoined: Vec<&str> = chunks
+            .iter()
+            .flat_map(|chunk| chunk.text.split_whitespace())
+            .filter(|word| !word.starts_with('#') && *word != "Basics")
+            .collect();
+        assert_eq!(rejoined, paragraph.split_whitespace().collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,285 @@
+use crate::chunk::Chunk;
+use crate::params::GenerationParams;
+use crate::reply::ReplyPair;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Number, Value};
+
+/// A generated question and its answer, with enough provenance to trace it
+/// back to the chunk, model and prompt it came from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QaPair {
+    pub question: String,
+    pub answer: String,
+    /// Kind of question, such as factual, inferential or thematic, when the
+    /// model gave one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub question_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub difficulty: Option<String>,
+    /// Corpus the chunk belongs to; `None` for ad-hoc text such as a webhook
+    /// body.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub corpus: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_index: Option<usize>,
+    /// `content_hash` of the chunk text.
+    pub chunk_hash: String,
+    /// Identifies the pair across reruns: a hash of the chunk hash and the
+    /// normalized question. Sinks use it to avoid writing a pair twice.
+    pub pair_hash: String,
+    /// The chunk the pair was generated from.
+    pub chunk_text: String,
+    /// Heading path of the chunk, for markdown sources.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub section: Option<String>,
+    /// Model and sampling parameters the pair was generated with.
+    pub generation: GenerationParams,
+    pub prompt_version: String,
+    pub generated_at: DateTime<Utc>,
+}
+
+impl QaPair {
+    /// Attaches provenance to a pair parsed from a reply for `chunk`.
+    pub fn from_reply(
+        pair: ReplyPair,
+        chunk: &Chunk,
+        generation: &GenerationParams,
+        prompt_version: &str,
+        generated_at: DateTime<Utc>,
+    ) -> Self {
+        let chunk_hash = content_hash(&chunk.text);
+        let pair_hash = pair_hash(&chunk_hash, &pair.question);
+        QaPair {
+            question: pair.question,
+            answer: pair.answer,
+            question_type: pair.question_type,
+            difficulty: pair.difficulty,
+            corpus: None,
+            chunk_index: None,
+            chunk_hash,
+            pair_hash,
+            chunk_text: chunk.text.clone(),
+            section: chunk.breadcrumb(),
+            generation: generation.clone(),
+            prompt_version: prompt_version.to_string(),
+            generated_at,
+        }
+    }
+
+    /// Records where the chunk sits: its corpus, if any, and its index.
+    pub fn located(mut self, corpus: Option<&str>, chunk_index: usize) -> Self {
+        self.corpus = corpus.map(str::to_string);
+        self.chunk_index = Some(chunk_index);
+        self
+    }
+}
+
+/// A `QaPair` field that sinks can write to a column of their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PairField {
+    Question,
+    Answer,
+    /// The corpus the chunk belongs to.
+    Source,
+    ChunkIndex,
+    ChunkHash,
+    PairHash,
+    ChunkText,
+    Section,
+    Model,
+    Temperature,
+    TopP,
+    MaxTokens,
+    Seed,
+    PresencePenalty,
+    FrequencyPenalty,
+    PromptVersion,
+    QuestionType,
+    Difficulty,
+    GeneratedAt,
+}
+
+impl PairField {
+    pub const ALL: [PairField; 19] = [
+        PairField::Question,
+        PairField::Answer,
+        PairField::Source,
+        PairField::ChunkIndex,
+        PairField::ChunkHash,
+        PairField::PairHash,
+        PairField::ChunkText,
+        PairField::Section,
+        PairField::Model,
+        PairField::Temperature,
+        PairField::TopP,
+        PairField::MaxTokens,
+        PairField::Seed,
+        PairField::PresencePenalty,
+        PairField::FrequencyPenalty,
+        PairField::PromptVersion,
+        PairField::QuestionType,
+        PairField::Difficulty,
+        PairField::GeneratedAt,
+    ];
+
+    /// Column name used when none is configured, e.g. "Chunk Index".
+    pub fn default_column(&self) -> &'static str {
+        match self {
+            PairField::Question => "Question",
+            PairField::Answer => "Answer",
+            PairField::Source => "Source",
+            PairField::ChunkIndex => "Chunk Index",
+            PairField::ChunkHash => "Chunk Hash",
+            PairField::PairHash => "Pair Hash",
+            PairField::ChunkText => "Chunk Text",
+            PairField::Section => "Section",
+            PairField::Model => "Model",
+            PairField::Temperature => "Temperature",
+            PairField::TopP => "Top P",
+            PairField::MaxTokens => "Max Tokens",
+            PairField::Seed => "Seed",
+            PairField::PresencePenalty => "Presence Penalty",
+            PairField::FrequencyPenalty => "Frequency Penalty",
+            PairField::PromptVersion => "Prompt Version",
+            PairField::QuestionType => "Question Type",
+            PairField::Difficulty => "Difficulty",
+            PairField::GeneratedAt => "Generated At",
+        }
+    }
+
+    /// The field's value for `pair`, or `None` when the pair lacks it.
+    pub fn value(&self, pair: &QaPair) -> Option<Value> {
+        let text = |s: &str| Some(Value::String(s.to_string()));
+        match self {
+            PairField::Question => text(&pair.question),
+            PairField::Answer => text(&pair.answer),
+            PairField::Source => pair.corpus.as_deref().and_then(text),
+            PairField::ChunkIndex => pair.chunk_index.map(Value::from),
+            PairField::ChunkHash => text(&pair.chunk_hash),
+            PairField::PairHash => text(&pair.pair_hash),
+            PairField::ChunkText => text(&pair.chunk_text),
+            PairField::Section => pair.section.as_deref().and_then(text),
+            PairField::Model => text(&pair.generation.model),
+            PairField::Temperature => pair.generation.temperature.and_then(float),
+            PairField::TopP => pair.generation.top_p.and_then(float),
+            PairField::MaxTokens => Some(Value::from(pair.generation.max_tokens)),
+            PairField::Seed => pair.generation.seed.map(Value::from),
+            PairField::PresencePenalty => pair.generation.presence_penalty.and_then(float),
+            PairField::FrequencyPenalty => pair.generation.frequency_penalty.and_then(float),
+            PairField::PromptVersion => text(&pair.prompt_version),
+            PairField::QuestionType => pair.question_type.as_deref().and_then(text),
+            PairField::Difficulty => pair.difficulty.as_deref().and_then(text),
+            PairField::GeneratedAt => text(&pair.generated_at.to_rfc3339()),
+        }
+    }
+
+    /// The field's value for `pair` as plain text, e.g. for a CSV cell.
+    pub fn text(&self, pair: &QaPair) -> Option<String> {
+        self.value(pair).map(|value| match value {
+            Value::String(s) => s,
+            value => value.to_string(),
+        })
+    }
+}
+
+/// `value` as a JSON number with the digits it was written with, e.g. 0.2
+/// rather than the 0.20000000298023224 a plain widening to f64 gives.
+fn float(value: f32) -> Option<Value> {
+    let widened = value.to_string().parse().ok()?;
+    Number::from_f64(widened).map(Value::Number)
+}
+
+/// Stable 64-bit FNV-1a hash of `text` as 16 hex digits. Unlike
+/// `DefaultHasher` it does not change between builds, so it can be stored.
+pub fn content_hash(text: &str) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in text.as_bytes() {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    format!("{:016x}", hash)
+}
+
+/// Hash of a chunk hash and a question. Case and whitespace are normalized
+/// away, so the same question generated again for the same chunk gets the
+/// same hash.
+pub fn pair_hash(chunk_hash: &str, question: &str) -> String {
+    let question = question
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase();
+    content_hash(&format!("{}\n{}", chunk_hash, question))
+}
+
+/// A pair for `question` and `answer` from a one-sentence chunk, for tests
+/// of code that writes pairs.
+#[cfg(test)]
+pub(crate) fn test_pair(question: &str, answer: &str) -> QaPair {
+    use chrono::TimeZone;
+    let reply = ReplyPair {
+        question: question.to_string(),
+        answer: answer.to_string(),
+        question_type: None,
+        difficulty: None,
+    };
+    let generated_at = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+    QaPair::from_reply(
+        reply,
+        &Chunk::new("Pods run containers."),
+        &GenerationParams::default(),
+        "2",
+        generated_at,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn pair() -> QaPair {
+        let params = GenerationParams {
+            temperature: Some(0.2),
+            seed: Some(42),
+            ..GenerationParams::default()
+        };
+        let reply = ReplyPair {
+            question: String::from("What is a pod?"),
+            answer: String::from("The smallest deployable unit."),
+            question_type: None,
+            difficulty: None,
+        };
+        let generated_at = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        QaPair::from_reply(
+            reply,
+            &Chunk::new("Pods run containers."),
+            &params,
+            "2",
+            generated_at,
+        )
+    }
+
+    #[test]
+    fn generation_params_map_to_fields() {
+        let pair = pair();
+        assert_eq!(PairField::Temperature.text(&pair).as_deref(), Some("0.2"));
+        assert_eq!(PairField::Seed.text(&pair).as_deref(), Some("42"));
+        assert_eq!(PairField::MaxTokens.text(&pair).as_deref(), Some("4000"));
+        assert_eq!(PairField::TopP.value(&pair), None);
+        assert_eq!(
+            PairField::Model.text(&pair).as_deref(),
+            Some(crate::params::DEFAULT_MODEL)
+        );
+    }
+
+    #[test]
+    fn serialized_pairs_keep_their_generation_params() {
+        let pair = pair();
+        let json = serde_json::to_value(&pair).unwrap();
+        assert_eq!(json["generation"]["seed"], 42);
+        assert_eq!(serde_json::from_value::<QaPair>(json).unwrap(), pair);
+    }
+}
@@ -0,0 +1,152 @@
+use async_openai::types::CreateChatCompletionRequestArgs;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::str::FromStr;
+
+pub const DEFAULT_MODEL: &str = "gpt-4-1106-preview";
+pub const DEFAULT_MAX_TOKENS: u16 = 4000;
+pub const DEFAULT_MAX_CONTINUATIONS: u32 = 2;
+pub const DEFAULT_MAX_REPAIRS: u32 = 2;
+
+/// Model, sampling and length settings for one generation run. Unset optional
+/// sampling parameters are left to the server's defaults.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GenerationParams {
+    #[serde(default = "default_model")]
+    pub model: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default = "default_max_tokens")]
+    pub max_tokens: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    /// How many follow-up requests to make for more pairs when a reply is
+    /// cut off at `max_tokens`.
+    #[serde(default = "default_max_continuations")]
+    pub max_continuations: u32,
+    /// How many times to send an unparseable reply back to the model, with
+    /// the parse error, asking for a corrected one.
+    #[serde(default = "default_max_repairs")]
+    pub max_repairs: u32,
+}
+
+fn default_model() -> String {
+    DEFAULT_MODEL.to_string()
+}
+
+fn default_max_tokens() -> u16 {
+    DEFAULT_MAX_TOKENS
+}
+
+fn default_max_continuations() -> u32 {
+    DEFAULT_MAX_CONTINUATIONS
+}
+
+fn default_max_repairs() -> u32 {
+    DEFAULT_MAX_REPAIRS
+}
+
+impl Default for GenerationParams {
+    fn default() -> Self {
+        GenerationParams {
+            model: default_model(),
+            temperature: None,
+            top_p: None,
+            max_tokens: DEFAULT_MAX_TOKENS,
+            seed: None,
+            presence_penalty: None,
+            frequency_penalty: None,
+            max_continuations: DEFAULT_MAX_CONTINUATIONS,
+            max_repairs: DEFAULT_MAX_REPAIRS,
+        }
+    }
+}
+
+impl GenerationParams {
+    /// Reads `MODEL`, `TEMPERATURE`, `TOP_P`, `MAX_TOKENS`, `SEED`,
+    /// `PRESENCE_PENALTY`, `FREQUENCY_PENALTY`, `MAX_CONTINUATIONS` and
+    /// `MAX_REPAIRS`, leaving defaults for anything unset.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut params = GenerationParams::default();
+        if let Ok(model) = env::var("MODEL") {
+            params.model = model;
+        }
+        if let Some(max_tokens) = parse_env("MAX_TOKENS")? {
+            params.max_tokens = max_tokens;
+        }
+        params.temperature = parse_env("TEMPERATURE")?;
+        params.top_p = parse_env("TOP_P")?;
+        params.seed = parse_env("SEED")?;
+        params.presence_penalty = parse_env("PRESENCE_PENALTY")?;
+        params.frequency_penalty = parse_env("FREQUENCY_PENALTY")?;
+        if let Some(max_continuations) = parse_env("MAX_CONTINUATIONS")? {
+            params.max_continuations = max_continuations;
+        }
+        if let Some(max_repairs) = parse_env("MAX_REPAIRS")? {
+            params.max_repairs = max_repairs;
+        }
+        Ok(params)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.model.trim().is_empty() {
+            anyhow::bail!("generation parameters have an empty model name");
+        }
+        if self.max_tokens == 0 {
+            anyhow::bail!("max_tokens must be greater than zero");
+        }
+        check_range("temperature", self.temperature, 0.0, 2.0)?;
+        check_range("top_p", self.top_p, 0.0, 1.0)?;
+        check_range("presence_penalty", self.presence_penalty, -2.0, 2.0)?;
+        check_range("frequency_penalty", self.frequency_penalty, -2.0, 2.0)?;
+        Ok(())
+    }
+
+    /// Sets these parameters on a chat completion request.
+    pub fn apply(&self, request: &mut CreateChatCompletionRequestArgs) {
+        request.model(&self.model).max_tokens(self.max_tokens);
+        if let Some(temperature) = self.temperature {
+            request.temperature(temperature);
+        }
+        if let Some(top_p) = self.top_p {
+            request.top_p(top_p);
+        }
+        if let Some(seed) = self.seed {
+            request.seed(seed);
+        }
+        if let Some(presence_penalty) = self.presence_penalty {
+            request.presence_penalty(presence_penalty);
+        }
+        if let Some(frequency_penalty) = self.frequency_penalty {
+            request.frequency_penalty(frequency_penalty);
+        }
+    }
+}
+
+fn parse_env<T: FromStr>(name: &str) -> anyhow::Result<Option<T>>
+where
+    T::Err: std::fmt::Display,
+{
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("invalid {} '{}': {}", name, value, e)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn check_range(name: &str, value: Option<f32>, min: f32, max: f32) -> anyhow::Result<()> {
+    match value {
+        Some(v) if !(min..=max).contains(&v) => {
+            anyhow::bail!("{} must be between {} and {}, got {}", name, min, max, v)
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,346 @@
+use crate::error::{Error, Result};
+use serde_json::Value;
+
+/// The reply shape requested from the model, quoted in repair prompts.
+pub const QA_PAIRS_SCHEMA: &str = r#"{"type":"object","required":["qa_pairs"],"properties":{"qa_pairs":{"type":"array","items":{"type":"object","required":["question","answer"],"properties":{"question":{"type":"string","minLength":1},"answer":{"type":"string","minLength":1},"type":{"type":"string"},"difficulty":{"type":"string"}}}}}}"#;
+
+/// Keys models use instead of "qa_pairs", compared after lowercasing and
+/// dropping `_` and `-`.
+const PAIR_LIST_KEYS: &[&str] = &[
+    "qapairs",
+    "pairs",
+    "qa",
+    "questions",
+    "questionsandanswers",
+    "questionanswerpairs",
+    "items",
+    "data",
+    "results",
+];
+
+/// A pair as the model wrote it, before provenance is attached.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplyPair {
+    pub question: String,
+    pub answer: String,
+    pub question_type: Option<String>,
+    pub difficulty: Option<String>,
+}
+
+/// Parses a reply into pairs and validates it against `QA_PAIRS_SCHEMA`.
+///
+/// Common deviations are tolerated: JSON wrapped in a code fence or in
+/// prose, a bare array of pairs, alternate names for the "qa_pairs" key, and
+/// "q"/"a" or differently cased field names. Anything else is reported as
+/// malformed JSON or a schema mismatch, with a message fit for a repair
+/// prompt.
+pub fn parse_pairs(content: &str) -> Result<Vec<ReplyPair>> {
+    let value = parse_json(content)?;
+    let items = match value {
+        Value::Array(items) => items,
+        Value::Object(mut object) => {
+            let key = object
+                .keys()
+                .find(|k| normalize_key(k) == "qapairs")
+                .or_else(|| {
+                    object
+                        .keys()
+                        .find(|k| PAIR_LIST_KEYS.contains(&normalize_key(k).as_str()))
+                })
+                .or_else(|| {
+                    // Any single array is taken to be the list of pairs.
+                    let mut arrays = object.iter().filter(|(_, v)| v.is_array());
+                    match (arrays.next(), arrays.next()) {
+                        (Some((k, _)), None) => Some(k),
+                        _ => None,
+                    }
+                })
+                .cloned();
+            match key {
+                Some(key) => match object.remove(&key) {
+                    Some(Value::Array(items)) => items,
+                    _ => {
+                        return Err(Error::SchemaMismatch(format!(
+                            "\"{}\" must be an array of question and answer objects",
+                            key
+                        )))
+                    }
+                },
+                None if field(&object, &["question", "q"]).is_some() => {
+                    vec![Value::Object(object)]
+                }
+                None => {
+                    return Err(Error::SchemaMismatch(String::from(
+                        "missing \"qa_pairs\" array",
+                    )))
+                }
+            }
+        }
+        _ => {
+            return Err(Error::SchemaMismatch(String::from(
+                "reply must be a JSON object with a \"qa_pairs\" array",
+            )))
+        }
+    };
+
+    items
+        .iter()
+        .enumerate()
+        .map(|(i, item)| pair_from_item(i, item))
+        .collect()
+}
+
+fn pair_from_item(i: usize, item: &Value) -> Result<ReplyPair> {
+    let object = item
+        .as_object()
+        .ok_or_else(|| Error::SchemaMismatch(format!("qa_pairs[{}] is not an object", i)))?;
+    let question = field(object, &["question", "q"]);
+    let answer = field(object, &["answer", "a"]);
+    match (question, answer) {
+        (Some(question), Some(answer)) => Ok(ReplyPair {
+            question,
+            answer,
+            question_type: field(object, &["type", "questiontype"]),
+            difficulty: field(object, &["difficulty"]),
+        }),
+        (None, _) => Err(Error::SchemaMismatch(format!(
+            "qa_pairs[{}] has no non-empty string \"question\"",
+            i
+        ))),
+        (_, None) => Err(Error::SchemaMismatch(format!(
+            "qa_pairs[{}] has no non-empty string \"answer\"",
+            i
+        ))),
+    }
+}
+
+/// Follow-up message asking the model to fix a reply that failed to parse.
+pub fn repair_prompt(error: &Error) -> String {
+    format!(
+        "Your previous reply could not be used: {}. Reply again with only a JSON object matching this JSON schema, and nothing else:\n{}",
+        error, QA_PAIRS_SCHEMA
+    )
+}
+
+fn parse_json(content: &str) -> Result<Value> {
+    let content = content.trim();
+    if let Ok(value) = serde_json::from_str(content) {
+        return Ok(value);
+    }
+    // Only look for a code fence once the plain parse has failed, since
+    // "```" inside a JSON string does not start one.
+    let unfenced = strip_code_fence(content);
+    let error = match serde_json::from_str(unfenced) {
+        Ok(value) => return Ok(value),
+        Err(e) => e,
+    };
+    // Retry on the outermost object or array, in case the JSON is
+    // surrounded by prose.
+    for candidate in [outermost(unfenced), outermost(content)]
+        .into_iter()
+        .flatten()
+    {
+        if let Ok(value) = serde_json::from_str(candidate) {
+            return Ok(value);
+        }
+    }
+    Err(Error::MalformedJson {
+        error: error.to_string(),
+        content: unfenced.to_string(),
+    })
+}
+
+/// The text from the first `{` or `[` to the last `}` or `]`.
+fn outermost(content: &str) -> Option<&str> {
+    let start = content.find(['{', '['])?;
+    let end = content.rfind(['}', ']'])?;
+    (start < end).then(|| &content[start..=end])
+}
+
+fn strip_code_fence(content: &str) -> &str {
+    let Some(start) = content.find("```") else {
+        return content;
+    };
+    let after = &content[start + 3..];
+    // Skip the info string, e.g. "json".
+    let body = match after.find('\n') {
+        Some(newline) => &after[newline + 1..],
+        None => after,
+    };
+    match body.rfind("```") {
+        Some(end) => body[..end].trim(),
+        None => body.trim(),
+    }
+}
+
+fn normalize_key(key: &str) -> String {
+    key.chars()
+        .filter(|c| *c != '_' && *c != '-')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+fn field(object: &serde_json::Map<String, Value>, names: &[&str]) -> Option<String> {
+    object
+        .iter()
+        .find(|(k, _)| names.contains(&normalize_key(k).as_str()))
+        .and_then(|(_, v)| v.as_str())
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// Recovers the pairs that were written out in full before a reply was cut
+/// off. Every complete object inside the first JSON array is tried; the
+/// trailing partial object, and anything that is not a pair, is skipped.
+pub fn salvage_pairs(partial: &str) -> Vec<ReplyPair> {
+    let start = match partial.find('[') {
+        Some(start) => start + 1,
+        None => return Vec::new(),
+    };
+
+    let mut pairs = Vec::new();
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut object_start = None;
+    for (i, c) in partial[start..].char_indices() {
+        let i = start + i;
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => {
+                if depth == 0 {
+                    object_start = Some(i);
+                }
+                depth += 1;
+            }
+            '}' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    if let Some(object_start) = object_start.take() {
+                        let pair = serde_json::from_str::<Value>(&partial[object_start..=i])
+                            .ok()
+                            .and_then(|item| pair_from_item(pairs.len(), &item).ok());
+                        pairs.extend(pair);
+                    }
+                }
+            }
+            ']' if depth == 0 => break,
+            _ => {}
+        }
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn questions(pairs: &[ReplyPair]) -> Vec<&str> {
+        pairs.iter().map(|pair| pair.question.as_str()).collect()
+    }
+
+    #[test]
+    fn backticks_inside_a_string_are_not_a_fence() {
+        let content = r#"{"qa_pairs":[{"question":"How do you print?","answer":"Use ```println!(\"hi\")``` in main."}]}"#;
+        let pairs = parse_pairs(content).unwrap();
+        assert_eq!(questions(&pairs), vec!["How do you print?"]);
+        assert_eq!(pairs[0].answer, r#"Use ```println!("hi")``` in main."#);
+    }
+
+    #[test]
+    fn parses_a_fenced_reply() {
+        let content = "```json\n{\"qa_pairs\":[{\"question\":\"Q?\",\"answer\":\"A.\"}]}\n```";
+        assert_eq!(questions(&parse_pairs(content).unwrap()), vec!["Q?"]);
+    }
+
+    #[test]
+    fn parses_json_surrounded_by_prose() {
+        let content = "Here are the pairs:\n{\"qa_pairs\":[{\"question\":\"Q?\",\"answer\":\"Use ``` fences.\"}]}\nHope this helps!";
+        assert_eq!(questions(&parse_pairs(content).unwrap()), vec!["Q?"]);
+    }
+
+    #[test]
+    fn reports_malformed_json() {
+        let err = parse_pairs("```json\n{\"qa_pairs\": [\n```").unwrap_err();
+        assert_eq!(err.kind(), "malformed_json");
+    }
+
+    #[test]
+    fn parses_a_bare_array() {
+        let content = r#"[{"question":"Q1?","answer":"A1."},{"question":"Q2?","answer":"A2."}]"#;
+        assert_eq!(
+            questions(&parse_pairs(content).unwrap()),
+            vec!["Q1?", "Q2?"]
+        );
+    }
+
+    #[test]
+    fn accepts_alternate_list_keys_and_field_casing() {
+        for content in [
+            r#"{"pairs":[{"question":"Q?","answer":"A."}]}"#,
+            r#"{"questions":[{"question":"Q?","answer":"A."}]}"#,
+            r#"{"QA-Pairs":[{"Question":"Q?","ANSWER":"A."}]}"#,
+            r#"{"Questions_And_Answers":[{"Q":"Q?","A":"A."}]}"#,
+        ] {
+            let pairs = parse_pairs(content).unwrap();
+            assert_eq!(questions(&pairs), vec!["Q?"], "{}", content);
+            assert_eq!(pairs[0].answer, "A.", "{}", content);
+        }
+    }
+
+    #[test]
+    fn takes_the_only_array_as_the_pairs() {
+        let content = r#"{"note":"three pairs","flashcards":[{"question":"Q?","answer":"A.","type":"factual","difficulty":"easy"}]}"#;
+        let pairs = parse_pairs(content).unwrap();
+        assert_eq!(questions(&pairs), vec!["Q?"]);
+        assert_eq!(pairs[0].question_type.as_deref(), Some("factual"));
+        assert_eq!(pairs[0].difficulty.as_deref(), Some("easy"));
+
+        let err = parse_pairs(r#"{"first":[],"second":[]}"#).unwrap_err();
+        assert_eq!(err.kind(), "schema_mismatch");
+    }
+
+    #[test]
+    fn parses_a_single_pair_object() {
+        let pairs = parse_pairs(r#"{"q":"Q?","a":"A."}"#).unwrap();
+        assert_eq!(questions(&pairs), vec!["Q?"]);
+        assert_eq!(pairs[0].answer, "A.");
+    }
+
+    #[test]
+    fn salvages_the_pairs_before_a_cut_off_object() {
+        let partial = r#"{"qa_pairs":[{"question":"Q1?","answer":"A1."},{"question":"Q2?","answer":"A2."},{"question":"Q3?","ans"#;
+        assert_eq!(questions(&salvage_pairs(partial)), vec!["Q1?", "Q2?"]);
+    }
+
+    #[test]
+    fn salvage_ignores_brackets_inside_strings() {
+        let partial = r#"{"qa_pairs":[{"question":"What does {} or ] mean?","answer":"A closing ] or } in a string."},{"question":"Q2?","answer":"cut off ]"#;
+        let pairs = salvage_pairs(partial);
+        assert_eq!(questions(&pairs), vec!["What does {} or ] mean?"]);
+        assert_eq!(pairs[0].answer, "A closing ] or } in a string.");
+    }
+
+    #[test]
+    fn salvage_handles_escaped_quotes() {
+        let partial = r#"[{"question":"What is \"}\"?","answer":"A \"brace\" and a backslash \\"},{"question":"Q2"#;
+        let pairs = salvage_pairs(partial);
+        assert_eq!(questions(&pairs), vec![r#"What is "}"?"#]);
+        assert_eq!(pairs[0].answer, r#"A "brace" and a backslash \"#);
+    }
+
+    #[test]
+    fn salvages_nothing_without_an_array() {
+        assert!(salvage_pairs(r#"{"question":"Q?","answer":"A."}"#).is_empty());
+        assert!(salvage_pairs("").is_empty());
+    }
+}
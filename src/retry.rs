@@ -0,0 +1,284 @@
+use crate::llm::{LlmBackend, LlmError};
+use async_openai::types::{CreateChatCompletionRequest, CreateChatCompletionResponse};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::env;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Exponential backoff with full jitter for retryable chat completion
+/// failures.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 5,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Reads `LLM_MAX_RETRIES`, `LLM_RETRY_BASE_MS` and `LLM_RETRY_MAX_MS`,
+    /// leaving defaults for anything unset.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut policy = RetryPolicy::default();
+        if let Ok(n) = env::var("LLM_MAX_RETRIES") {
+            policy.max_retries = n
+                .parse()
+                .map_err(|e| anyhow::anyhow!("invalid LLM_MAX_RETRIES '{}': {}", n, e))?;
+        }
+        if let Ok(ms) = env::var("LLM_RETRY_BASE_MS") {
+            policy.base_delay = Duration::from_millis(
+                ms.parse()
+                    .map_err(|e| anyhow::anyhow!("invalid LLM_RETRY_BASE_MS '{}': {}", ms, e))?,
+            );
+        }
+        if let Ok(ms) = env::var("LLM_RETRY_MAX_MS") {
+            policy.max_delay = Duration::from_millis(
+                ms.parse()
+                    .map_err(|e| anyhow::anyhow!("invalid LLM_RETRY_MAX_MS '{}': {}", ms, e))?,
+            );
+        }
+        Ok(policy)
+    }
+
+    /// Delay before retry number `attempt` (starting at 0): the server's
+    /// `Retry-After` when it sent one, otherwise a random duration up to
+    /// `base_delay * 2^attempt`. Either way it is capped at `max_delay`.
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        if let Some(retry_after) = retry_after {
+            return retry_after.min(self.max_delay);
+        }
+        let ceiling = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+        ceiling.mul_f64(jitter())
+    }
+
+    /// Delay before retrying after `error` on retry number `attempt`, or
+    /// `None` when the error should be returned instead.
+    pub fn retry_delay(&self, attempt: u32, error: &LlmError) -> Option<Duration> {
+        (error.is_retryable() && attempt < self.max_retries)
+            .then(|| self.delay(attempt, error.retry_after()))
+    }
+}
+
+/// Wraps a backend so that retryable failures are retried according to a
+/// `RetryPolicy`. Fatal errors, and the last retryable error once retries
+/// run out, are returned as they are.
+pub struct Retrying<B> {
+    inner: B,
+    policy: RetryPolicy,
+}
+
+impl<B: LlmBackend> Retrying<B> {
+    pub fn new(inner: B, policy: RetryPolicy) -> Self {
+        Retrying { inner, policy }
+    }
+}
+
+#[async_trait(?Send)]
+impl<B: LlmBackend> LlmBackend for Retrying<B> {
+    async fn chat(
+        &self,
+        request: &CreateChatCompletionRequest,
+    ) -> Result<CreateChatCompletionResponse, LlmError> {
+        let mut attempt = 0;
+        loop {
+            let e = match self.inner.chat(request).await {
+                Err(e) => e,
+                res => return res,
+            };
+            let Some(delay) = self.policy.retry_delay(attempt, &e) else {
+                return Err(e);
+            };
+            attempt += 1;
+            log::warn!(
+                "{}; retry {} of {} in {:.1}s.",
+                e,
+                attempt,
+                self.policy.max_retries,
+                delay.as_secs_f64()
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// Reads a `Retry-After` header, which is either a number of seconds or an
+/// HTTP date. A date in the past means no wait.
+pub fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (at.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// A value in [0, 1) that is random enough to spread out retries, without
+/// pulling in a random number generator.
+fn jitter() -> f64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    // Scramble the low bits, which change fastest between calls.
+    let mixed = nanos.wrapping_mul(2_654_435_761) % 1_000_000;
+    mixed as f64 / 1_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::testing::{reply, Scripted};
+    use async_openai::types::CreateChatCompletionRequestArgs;
+    use chrono::TimeZone;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+
+    #[test]
+    fn retry_after_is_capped_at_max_delay() {
+        let policy = policy();
+        assert_eq!(
+            policy.delay(0, Some(Duration::from_secs(5))),
+            Duration::from_secs(5)
+        );
+        assert_eq!(
+            policy.delay(0, Some(Duration::from_secs(3600))),
+            Duration::from_secs(30)
+        );
+    }
+
+    #[test]
+    fn backoff_stays_under_max_delay() {
+        let policy = policy();
+        for attempt in 0..40 {
+            assert!(policy.delay(attempt, None) <= policy.max_delay);
+        }
+    }
+
+    #[test]
+    fn parses_retry_after_seconds_and_dates() {
+        let now = Utc.with_ymd_and_hms(2015, 10, 21, 7, 28, 0).unwrap();
+        assert_eq!(
+            parse_retry_after(" 120 ", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+        assert_eq!(parse_retry_after("-5", now), None);
+    }
+
+    fn error(
+        status: u16,
+        retry_after: Option<Duration>,
+    ) -> Result<CreateChatCompletionResponse, LlmError> {
+        Err(LlmError::from_status(
+            status,
+            format!("status {}", status),
+            retry_after,
+        ))
+    }
+
+    fn retrying(
+        replies: Vec<Result<CreateChatCompletionResponse, LlmError>>,
+    ) -> Retrying<Scripted> {
+        let policy = RetryPolicy {
+            max_retries: 2,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::from_secs(30),
+        };
+        Retrying::new(Scripted::new(replies), policy)
+    }
+
+    fn request() -> CreateChatCompletionRequest {
+        CreateChatCompletionRequestArgs::default()
+            .model("test-model")
+            .messages([])
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn retries_rate_limits_and_server_errors() {
+        let backend = retrying(vec![
+            error(429, None),
+            error(503, None),
+            reply("{}", "stop"),
+        ]);
+        assert!(backend.chat(&request()).await.is_ok());
+        assert_eq!(backend.inner.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retries() {
+        let backend = retrying(vec![
+            error(500, None),
+            error(502, None),
+            error(429, None),
+            reply("{}", "stop"),
+        ]);
+        let err = backend.chat(&request()).await.unwrap_err();
+        assert!(matches!(
+            err,
+            LlmError::Retryable {
+                status: Some(429),
+                ..
+            }
+        ));
+        assert_eq!(backend.inner.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn returns_client_errors_without_retrying() {
+        for status in [400, 401] {
+            let backend = retrying(vec![error(status, None), reply("{}", "stop")]);
+            let err = backend.chat(&request()).await.unwrap_err();
+            assert!(matches!(err, LlmError::Fatal { status: Some(s), .. } if s == status));
+            assert_eq!(backend.inner.requests().len(), 1);
+        }
+    }
+
+    #[test]
+    fn waits_for_retry_after_when_retrying() {
+        let policy = RetryPolicy {
+            base_delay: Duration::ZERO,
+            ..policy()
+        };
+        let retry_after = Some(Duration::from_secs(7));
+        let rate_limited = LlmError::from_status(429, String::from("slow down"), retry_after);
+        assert_eq!(
+            policy.retry_delay(0, &rate_limited),
+            Some(Duration::from_secs(7))
+        );
+        assert_eq!(policy.retry_delay(3, &rate_limited), None);
+        let unauthorized = LlmError::from_status(401, String::from("bad key"), retry_after);
+        assert_eq!(policy.retry_delay(0, &unauthorized), None);
+    }
+}
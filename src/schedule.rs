@@ -0,0 +1,197 @@
+use chrono::{DateTime, Datelike, Duration, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use std::env;
+
+pub const DEFAULT_DELAY_MINUTES: i64 = 2;
+
+/// When and how often jobs run. Cron expressions are rendered in `timezone`,
+/// which must match the zone the platform evaluates them in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduleConfig {
+    pub timezone: Tz,
+    /// Minutes from now until a one-shot job fires.
+    pub delay_minutes: i64,
+    /// Minutes between runs of a recurring job; `None` schedules one-shot
+    /// jobs only.
+    pub every_minutes: Option<u32>,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        ScheduleConfig {
+            timezone: Tz::UTC,
+            delay_minutes: DEFAULT_DELAY_MINUTES,
+            every_minutes: None,
+        }
+    }
+}
+
+impl ScheduleConfig {
+    /// Reads `SCHEDULE_TZ` (an IANA zone name), `SCHEDULE_DELAY_MINUTES` and
+    /// `SCHEDULE_EVERY_MINUTES`, leaving defaults for anything unset.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut config = ScheduleConfig::default();
+        if let Ok(tz) = env::var("SCHEDULE_TZ") {
+            config.timezone = tz
+                .parse()
+                .map_err(|e| anyhow::anyhow!("invalid SCHEDULE_TZ '{}': {}", tz, e))?;
+        }
+        if let Ok(delay) = env::var("SCHEDULE_DELAY_MINUTES") {
+            config.delay_minutes = delay.parse().map_err(|e| {
+                anyhow::anyhow!("invalid SCHEDULE_DELAY_MINUTES '{}': {}", delay, e)
+            })?;
+        }
+        if let Ok(every) = env::var("SCHEDULE_EVERY_MINUTES") {
+            config.every_minutes = Some(every.parse().map_err(|e| {
+                anyhow::anyhow!("invalid SCHEDULE_EVERY_MINUTES '{}': {}", every, e)
+            })?);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.delay_minutes < 1 {
+            anyhow::bail!("schedule delay must be at least one minute");
+        }
+        if let Some(every) = self.every_minutes {
+            recurring_cron(every)?;
+        }
+        Ok(())
+    }
+
+    /// Cron expression for a job that fires once, `delay_minutes` from now.
+    pub fn one_shot(&self) -> String {
+        one_shot_cron(
+            Utc::now().with_timezone(&self.timezone),
+            Duration::minutes(self.delay_minutes),
+        )
+    }
+
+    /// Cron expression for the job scheduled on deploy: recurring when
+    /// `every_minutes` is set, one-shot otherwise.
+    pub fn initial(&self) -> anyhow::Result<String> {
+        match self.every_minutes {
+            Some(every) => recurring_cron(every),
+            None => Ok(self.one_shot()),
+        }
+    }
+}
+
+/// Renders `now + delay` as "minute hour day month *". The addition is done
+/// on the calendar, so it carries into the next hour, day, month and year.
+pub fn one_shot_cron<Z: TimeZone>(now: DateTime<Z>, delay: Duration) -> String {
+    let at = now + delay;
+    format!(
+        "{:02} {:02} {:02} {:02} *",
+        at.minute(),
+        at.hour(),
+        at.day(),
+        at.month()
+    )
+}
+
+/// Cron expression firing every `minutes` minutes. Cron can only express
+/// intervals that divide an hour or a day evenly.
+pub fn recurring_cron(minutes: u32) -> anyhow::Result<String> {
+    match minutes {
+        0 => anyhow::bail!("recurrence interval must be at least one minute"),
+        m if m < 60 && 60 % m == 0 => Ok(format!("*/{} * * * *", m)),
+        m if m % 60 == 0 && 24 % (m / 60) == 0 => Ok(format!("0 */{} * * *", m / 60)),
+        m => anyhow::bail!(
+            "a recurrence of {} minutes cannot be expressed in cron, use a divisor of 60 minutes or of 24 hours",
+            m
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap()
+    }
+
+    fn two_minutes_after(now: DateTime<Utc>) -> String {
+        one_shot_cron(now, Duration::minutes(2))
+    }
+
+    #[test]
+    fn one_shot_carries_into_next_hour() {
+        assert_eq!(two_minutes_after(utc(2023, 6, 15, 10, 58)), "00 11 15 06 *");
+    }
+
+    #[test]
+    fn one_shot_carries_into_next_day() {
+        assert_eq!(two_minutes_after(utc(2023, 6, 15, 23, 59)), "01 00 16 06 *");
+    }
+
+    #[test]
+    fn one_shot_carries_into_next_year() {
+        assert_eq!(
+            two_minutes_after(utc(2023, 12, 31, 23, 59)),
+            "01 00 01 01 *"
+        );
+    }
+
+    #[test]
+    fn one_shot_carries_from_january_into_february() {
+        assert_eq!(two_minutes_after(utc(2024, 1, 31, 23, 59)), "01 00 01 02 *");
+    }
+
+    #[test]
+    fn one_shot_carries_out_of_february() {
+        // 2023 is not a leap year, so Feb 28 is the last day.
+        assert_eq!(two_minutes_after(utc(2023, 2, 28, 23, 59)), "01 00 01 03 *");
+        // 2024 is, so Feb 28 rolls into Feb 29 and Feb 29 into March.
+        assert_eq!(two_minutes_after(utc(2024, 2, 28, 23, 59)), "01 00 29 02 *");
+        assert_eq!(two_minutes_after(utc(2024, 2, 29, 23, 59)), "01 00 01 03 *");
+    }
+
+    #[test]
+    fn one_shot_renders_in_the_configured_zone() {
+        let tokyo: Tz = "Asia/Tokyo".parse().unwrap();
+        // 16:59 UTC on Dec 31 is already 01:59 on Jan 1 in Tokyo.
+        let now = utc(2023, 12, 31, 16, 59).with_timezone(&tokyo);
+        assert_eq!(one_shot_cron(now, Duration::minutes(2)), "01 02 01 01 *");
+
+        // New York skips 02:00-03:00 when daylight saving time starts.
+        let new_york: Tz = "America/New_York".parse().unwrap();
+        let now = utc(2024, 3, 10, 6, 59).with_timezone(&new_york);
+        assert_eq!(one_shot_cron(now, Duration::minutes(2)), "01 03 10 03 *");
+    }
+
+    #[test]
+    fn recurring_accepts_divisors_of_an_hour_or_a_day() {
+        assert_eq!(recurring_cron(15).unwrap(), "*/15 * * * *");
+        assert_eq!(recurring_cron(120).unwrap(), "0 */2 * * *");
+        assert_eq!(recurring_cron(1440).unwrap(), "0 */24 * * *");
+    }
+
+    #[test]
+    fn recurring_rejects_other_intervals() {
+        for minutes in [0, 7, 45, 90, 2880] {
+            assert!(
+                recurring_cron(minutes).is_err(),
+                "{} minutes should be rejected",
+                minutes
+            );
+        }
+    }
+
+    #[test]
+    fn validate_rejects_bad_settings() {
+        let config = ScheduleConfig {
+            delay_minutes: 0,
+            ..ScheduleConfig::default()
+        };
+        assert!(config.validate().is_err());
+        let config = ScheduleConfig {
+            every_minutes: Some(7),
+            ..ScheduleConfig::default()
+        };
+        assert!(config.validate().is_err());
+        assert!(ScheduleConfig::default().validate().is_ok());
+    }
+}
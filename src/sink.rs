@@ -0,0 +1,424 @@
+use crate::airtable::{AirtableClient, AirtableConfig, AirtableError, RecordWriter};
+use crate::csv::CsvSink;
+use crate::error::Result;
+use crate::finetune::FineTuneSink;
+use crate::job::Sink;
+use crate::pair::QaPair;
+use crate::retry::RetryPolicy;
+use airtable_flows::create_record;
+use async_trait::async_trait;
+use serde_json::Value;
+use std::collections::{HashSet, VecDeque};
+use std::env;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_DEAD_LETTER_FILE: &str = "dead_letter.jsonl";
+/// Most records Airtable accepts in one request.
+pub const AIRTABLE_BATCH_SIZE: usize = 10;
+/// Most requests Airtable accepts per second for one base.
+pub const AIRTABLE_REQUESTS_PER_SEC: usize = 5;
+
+/// How many pairs reached the sink.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteReport {
+    pub written: usize,
+    /// Pairs that could not be written, even after retries. They are kept
+    /// in the dead-letter file.
+    pub failed: usize,
+    /// Pairs handed to a writer that does not report whether they arrived,
+    /// such as the flows.network Airtable integration.
+    pub unchecked: usize,
+}
+
+impl WriteReport {
+    pub fn add(&mut self, other: WriteReport) {
+        self.written += other.written;
+        self.failed += other.failed;
+        self.unchecked += other.unchecked;
+    }
+}
+
+/// Where the pipeline writes pairs. Generation never writes anywhere by
+/// itself; the pipeline creates one sink per run, writes each chunk's pairs
+/// once they are ready and flushes before it finishes.
+#[async_trait(?Send)]
+pub trait PairSink {
+    /// Writes pairs, or queues them until there are enough for a batch.
+    async fn write(&mut self, pairs: &[QaPair]) -> Result<WriteReport>;
+
+    /// Writes everything still queued.
+    async fn flush(&mut self) -> Result<WriteReport>;
+}
+
+/// Builds the sink for `sink` from its environment variables.
+pub fn sink_from_env(sink: Sink) -> anyhow::Result<Box<dyn PairSink>> {
+    match sink {
+        Sink::Airtable => Ok(Box::new(AirtableSink::from_env()?)),
+        Sink::Csv => Ok(Box::new(CsvSink::from_env()?)),
+        Sink::Finetune => Ok(Box::new(FineTuneSink::from_env()?)),
+        Sink::None => Ok(Box::new(Discard)),
+    }
+}
+
+/// Drops every pair, for runs that only generate and log.
+pub struct Discard;
+
+#[async_trait(?Send)]
+impl PairSink for Discard {
+    async fn write(&mut self, _pairs: &[QaPair]) -> Result<WriteReport> {
+        Ok(WriteReport::default())
+    }
+
+    async fn flush(&mut self) -> Result<WriteReport> {
+        Ok(WriteReport::default())
+    }
+}
+
+/// Writes pairs to Airtable in batches of `AIRTABLE_BATCH_SIZE`, at most
+/// `AIRTABLE_REQUESTS_PER_SEC` requests per second.
+///
+/// With an API key, batches go through the Airtable REST API: transient
+/// failures are retried and pairs that still cannot be written are
+/// dead-lettered. Without one, each pair goes through the flows.network
+/// integration, whose results cannot be checked.
+pub struct AirtableSink<C: RecordWriter = AirtableClient> {
+    config: AirtableConfig,
+    client: Option<C>,
+    policy: RetryPolicy,
+    queue: Vec<QaPair>,
+    limiter: RateLimiter,
+}
+
+impl AirtableSink {
+    pub fn new(config: AirtableConfig) -> Self {
+        if config.api_key.is_none() {
+            log::warn!(
+                "airtable_api_key is not set, Airtable write results cannot be checked and reruns may duplicate records."
+            );
+        }
+        let client = config.client();
+        AirtableSink::with_client(config, client)
+    }
+
+    pub fn from_env() -> anyhow::Result<Self> {
+        let config = AirtableConfig::from_env()?;
+        config.check_key_column()?;
+        Ok(AirtableSink::new(config))
+    }
+}
+
+impl<C: RecordWriter> AirtableSink<C> {
+    /// Writes through `client`, or through the flows.network integration
+    /// when there is none.
+    pub fn with_client(config: AirtableConfig, client: Option<C>) -> Self {
+        AirtableSink {
+            client,
+            config,
+            policy: RetryPolicy::default(),
+            queue: Vec::new(),
+            limiter: RateLimiter::new(AIRTABLE_REQUESTS_PER_SEC, Duration::from_secs(1)),
+        }
+    }
+
+    async fn write_batches(&mut self, pairs: &[QaPair]) -> WriteReport {
+        self.queue.extend_from_slice(pairs);
+        let mut report = WriteReport::default();
+        while self.queue.len() >= AIRTABLE_BATCH_SIZE {
+            let batch: Vec<QaPair> = self.queue.drain(..AIRTABLE_BATCH_SIZE).collect();
+            report.add(self.send(&batch).await);
+        }
+        report
+    }
+
+    async fn flush_queue(&mut self) -> WriteReport {
+        let mut report = WriteReport::default();
+        while !self.queue.is_empty() {
+            let n = self.queue.len().min(AIRTABLE_BATCH_SIZE);
+            let batch: Vec<QaPair> = self.queue.drain(..n).collect();
+            report.add(self.send(&batch).await);
+        }
+        report
+    }
+
+    async fn send(&mut self, batch: &[QaPair]) -> WriteReport {
+        let Some(client) = &self.client else {
+            // The integration can only insert, so there is no key to send.
+            for pair in batch {
+                upload_record(&self.config, self.config.fields.record(pair));
+            }
+            return WriteReport {
+                unchecked: batch.len(),
+                ..WriteReport::default()
+            };
+        };
+
+        let fields = self.records(batch);
+        let mut attempt = 0;
+        let res = loop {
+            let wait = self.limiter.reserve(Instant::now());
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+            let res = match &self.config.key_column {
+                Some(key_column) => client.upsert_records(&fields, key_column),
+                None => client.create_records(&fields),
+            };
+            match res {
+                Err(e) if e.is_retryable() && attempt < self.policy.max_retries => {
+                    let delay = self.policy.delay(attempt, e.retry_after);
+                    attempt += 1;
+                    log::warn!(
+                        "{}; retry {} of {} in {:.1}s.",
+                        e,
+                        attempt,
+                        self.policy.max_retries,
+                        delay.as_secs_f64()
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                res => break res,
+            }
+        };
+        match res {
+            Ok(()) => WriteReport {
+                written: fields.len(),
+                ..WriteReport::default()
+            },
+            Err(e) => {
+                log::error!("Failed to write {} pairs to Airtable: {}", batch.len(), e);
+                for pair in batch {
+                    dead_letter(pair, &e);
+                }
+                WriteReport {
+                    failed: batch.len(),
+                    ..WriteReport::default()
+                }
+            }
+        }
+    }
+
+    /// The records for `batch`. When upserting, a pair that appears twice is
+    /// sent once, since Airtable rejects an upsert that matches the same key
+    /// twice.
+    fn records(&self, batch: &[QaPair]) -> Vec<Value> {
+        let mut seen = HashSet::new();
+        batch
+            .iter()
+            .filter(|pair| self.config.key_column.is_none() || seen.insert(pair.pair_hash.as_str()))
+            .map(|pair| self.record(pair))
+            .collect()
+    }
+
+    /// The record for `pair`: its mapped fields, plus its key when upserting.
+    fn record(&self, pair: &QaPair) -> Value {
+        let mut record = self.config.fields.record(pair);
+        if let (Some(key_column), Value::Object(fields)) = (&self.config.key_column, &mut record) {
+            fields.insert(key_column.clone(), Value::String(pair.pair_hash.clone()));
+        }
+        record
+    }
+}
+
+/// Allows at most `limit` requests in any `window`.
+struct RateLimiter {
+    limit: usize,
+    window: Duration,
+    /// Start times of the most recent requests.
+    sent: VecDeque<Instant>,
+}
+
+impl RateLimiter {
+    fn new(limit: usize, window: Duration) -> Self {
+        RateLimiter {
+            limit,
+            window,
+            sent: VecDeque::new(),
+        }
+    }
+
+    /// Books a request wanted at `now` and returns how long to wait before
+    /// sending it.
+    fn reserve(&mut self, now: Instant) -> Duration {
+        let mut wait = Duration::ZERO;
+        if self.sent.len() >= self.limit {
+            if let Some(oldest) = self.sent.pop_front() {
+                wait = (oldest + self.window).saturating_duration_since(now);
+            }
+        }
+        self.sent.push_back(now + wait);
+        wait
+    }
+}
+
+#[async_trait(?Send)]
+impl<C: RecordWriter> PairSink for AirtableSink<C> {
+    async fn write(&mut self, pairs: &[QaPair]) -> Result<WriteReport> {
+        Ok(self.write_batches(pairs).await)
+    }
+
+    async fn flush(&mut self) -> Result<WriteReport> {
+        Ok(self.flush_queue().await)
+    }
+}
+
+/// Appends a pair that could not be written, with the reason, to the file
+/// named by `DEAD_LETTER_FILE`, so it can be re-imported by hand. If even
+/// that fails, the pair is logged instead of lost.
+fn dead_letter(pair: &QaPair, error: &AirtableError) {
+    let path = env::var("DEAD_LETTER_FILE").unwrap_or(DEFAULT_DEAD_LETTER_FILE.to_string());
+    let line = serde_json::json!({
+        "error": error.to_string(),
+        "pair": pair,
+    })
+    .to_string();
+    let res = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| writeln!(file, "{}", line));
+    if let Err(e) = res {
+        log::error!(
+            "Failed to write to dead-letter file '{}': {}. Lost pair: {}",
+            path,
+            e,
+            line
+        );
+    }
+}
+
+pub async fn upload_airtable(question: &str, answer: &str) {
+    match AirtableConfig::from_env() {
+        Ok(config) => upload_record(
+            &config,
+            serde_json::json!({
+                "Question": question,
+                "Answer": answer,
+            }),
+        ),
+        Err(e) => log::error!("Invalid Airtable configuration: {}", e),
+    }
+}
+
+/// Creates a record through the flows.network Airtable integration.
+fn upload_record(config: &AirtableConfig, data: Value) {
+    let Some(token_name) = &config.token_name else {
+        log::error!("airtable_token_name is not set, the record was not written.");
+        return;
+    };
+    // The integration reports nothing back, so the write cannot be checked.
+    create_record(token_name, &config.base_id, &config.table, data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::airtable::FieldMap;
+    use crate::pair::test_pair;
+    use serde_json::json;
+    use std::cell::RefCell;
+
+    /// Records the size of each batch instead of sending it.
+    #[derive(Default)]
+    struct Recorder {
+        batches: RefCell<Vec<usize>>,
+    }
+
+    impl RecordWriter for Recorder {
+        fn create_records(&self, fields: &[Value]) -> std::result::Result<(), AirtableError> {
+            self.batches.borrow_mut().push(fields.len());
+            Ok(())
+        }
+
+        fn upsert_records(
+            &self,
+            fields: &[Value],
+            _key_column: &str,
+        ) -> std::result::Result<(), AirtableError> {
+            self.create_records(fields)
+        }
+    }
+
+    fn config(key_column: Option<&str>) -> AirtableConfig {
+        AirtableConfig {
+            base_id: String::from("appXYZ"),
+            table: String::from("pairs"),
+            api_key: Some(String::from("pat123")),
+            token_name: None,
+            fields: FieldMap::default(),
+            key_column: key_column.map(str::to_string),
+        }
+    }
+
+    fn sink(key_column: Option<&str>) -> AirtableSink {
+        AirtableSink::new(config(key_column))
+    }
+
+    #[test]
+    fn records_carry_the_key_when_upserting() {
+        let pair = test_pair("What runs pods?", "Nodes.");
+        assert_eq!(
+            sink(Some("Pair Hash")).record(&pair),
+            json!({
+                "Question": "What runs pods?",
+                "Answer": "Nodes.",
+                "Pair Hash": pair.pair_hash,
+            })
+        );
+        assert_eq!(
+            sink(None).record(&pair),
+            json!({ "Question": "What runs pods?", "Answer": "Nodes." })
+        );
+    }
+
+    #[test]
+    fn upserts_send_each_pair_once_per_batch() {
+        // Case and spacing do not change the pair hash.
+        let batch = [
+            test_pair("What runs pods?", "Nodes."),
+            test_pair("Why?", "Because."),
+            test_pair("what  runs pods?", "Nodes again."),
+        ];
+        let records = sink(Some("Pair Hash")).records(&batch);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["Answer"], "Nodes.");
+        assert_eq!(records[1]["Answer"], "Because.");
+
+        assert_eq!(sink(None).records(&batch).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn sends_full_batches_and_flushes_the_rest() {
+        let pairs: Vec<QaPair> = (0..23)
+            .map(|i| test_pair(&format!("Question {}?", i), "Answer."))
+            .collect();
+        let mut sink = AirtableSink::with_client(config(None), Some(Recorder::default()));
+
+        let written = sink.write(&pairs).await.unwrap();
+        assert_eq!(written.written, 20);
+        let flushed = sink.flush().await.unwrap();
+        assert_eq!(flushed.written, 3);
+
+        let recorder = sink.client.unwrap();
+        assert_eq!(*recorder.batches.borrow(), vec![10, 10, 3]);
+    }
+
+    #[test]
+    fn waits_when_a_request_would_exceed_the_rate_limit() {
+        let mut limiter = RateLimiter::new(AIRTABLE_REQUESTS_PER_SEC, Duration::from_secs(1));
+        let start = Instant::now();
+        for i in 0..5 {
+            let now = start + Duration::from_millis(i * 100);
+            assert_eq!(limiter.reserve(now), Duration::ZERO);
+        }
+        // The sixth request is 500ms after the first, so it waits the other
+        // 500ms; the seventh then waits for the second to leave the window.
+        let now = start + Duration::from_millis(500);
+        assert_eq!(limiter.reserve(now), Duration::from_millis(500));
+        assert_eq!(limiter.reserve(now), Duration::from_millis(600));
+        // Once a full window has passed there is no wait.
+        let later = start + Duration::from_secs(3);
+        assert_eq!(limiter.reserve(later), Duration::ZERO);
+    }
+}
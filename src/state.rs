@@ -0,0 +1,214 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ChunkStatus {
+    Done { pairs: usize },
+    Failed { error: String },
+}
+
+/// Progress of one job, persisted after every chunk so a re-triggered job
+/// resumes where the previous invocation stopped.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Progress {
+    /// Highest chunk index that completed successfully.
+    pub last_completed: Option<usize>,
+    pub chunks: BTreeMap<usize, ChunkStatus>,
+}
+
+impl Progress {
+    pub fn is_done(&self, index: usize) -> bool {
+        matches!(self.chunks.get(&index), Some(ChunkStatus::Done { .. }))
+    }
+
+    /// Whether the chunk has a recorded outcome, done or failed.
+    pub fn is_attempted(&self, index: usize) -> bool {
+        self.chunks.contains_key(&index)
+    }
+
+    /// Whether a run should skip the chunk. Done chunks are always skipped.
+    /// In drip mode failed chunks are skipped too, so a chunk that keeps
+    /// failing cannot stall every tick.
+    pub fn should_skip(&self, index: usize, drip: bool) -> bool {
+        self.is_done(index) || (drip && self.is_attempted(index))
+    }
+
+    pub fn mark_done(&mut self, index: usize, pairs: usize) {
+        self.chunks.insert(index, ChunkStatus::Done { pairs });
+        self.last_completed = Some(self.last_completed.map_or(index, |last| last.max(index)));
+    }
+
+    pub fn mark_failed(&mut self, index: usize, error: String) {
+        self.chunks.insert(index, ChunkStatus::Failed { error });
+    }
+}
+
+pub trait StateStore {
+    fn load(&self, key: &str) -> anyhow::Result<Option<Progress>>;
+    fn save(&self, key: &str, progress: &Progress) -> anyhow::Result<()>;
+    fn clear(&self, key: &str) -> anyhow::Result<()>;
+}
+
+/// Keeps progress in the flows.network key-value store.
+pub struct FlowsStore;
+
+impl StateStore for FlowsStore {
+    fn load(&self, key: &str) -> anyhow::Result<Option<Progress>> {
+        match store_flows::get(key) {
+            Some(value) => serde_json::from_value(value)
+                .map(Some)
+                .map_err(|e| anyhow::anyhow!("failed to parse progress for '{}': {}", key, e)),
+            None => Ok(None),
+        }
+    }
+
+    fn save(&self, key: &str, progress: &Progress) -> anyhow::Result<()> {
+        store_flows::set(key, serde_json::to_value(progress)?, None);
+        Ok(())
+    }
+
+    fn clear(&self, key: &str) -> anyhow::Result<()> {
+        store_flows::del(key);
+        Ok(())
+    }
+}
+
+/// Keeps progress as one JSON file per job under `dir`, for local runs and
+/// tests.
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        FileStore { dir: dir.into() }
+    }
+
+    /// File for `key`. Lowercase letters, digits and `-` are kept and every
+    /// other byte becomes `_` and two lowercase hex digits, so distinct keys
+    /// never share a file, even on a case-insensitive file system.
+    fn path(&self, key: &str) -> PathBuf {
+        let mut file_name = String::with_capacity(key.len());
+        for byte in key.bytes() {
+            if byte.is_ascii_lowercase() || byte.is_ascii_digit() || byte == b'-' {
+                file_name.push(byte as char);
+            } else {
+                file_name.push_str(&format!("_{:02x}", byte));
+            }
+        }
+        self.dir.join(format!("{}.json", file_name))
+    }
+}
+
+impl StateStore for FileStore {
+    fn load(&self, key: &str) -> anyhow::Result<Option<Progress>> {
+        let path = self.path(key);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let contents = fs::read_to_string(&path)?;
+        serde_json::from_str(&contents)
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("failed to parse {}: {}", path.display(), e))
+    }
+
+    fn save(&self, key: &str, progress: &Progress) -> anyhow::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.path(key), serde_json::to_string_pretty(progress)?)?;
+        Ok(())
+    }
+
+    fn clear(&self, key: &str) -> anyhow::Result<()> {
+        let path = self.path(key);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Uses a `FileStore` under `STATE_DIR` when it is set and the flows.network
+/// store otherwise.
+pub fn state_store_from_env() -> Box<dyn StateStore> {
+    match std::env::var("STATE_DIR") {
+        Ok(dir) => Box::new(FileStore::new(dir)),
+        Err(_) => Box::new(FlowsStore),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_store_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::new(dir.path());
+        let key = "progress:k8s:gpt-4";
+        assert_eq!(store.load(key).unwrap(), None);
+
+        let mut progress = Progress::default();
+        progress.mark_done(0, 12);
+        progress.mark_failed(1, String::from("malformed_json: bad reply"));
+        progress.mark_done(2, 7);
+        store.save(key, &progress).unwrap();
+        assert_eq!(store.load(key).unwrap(), Some(progress));
+
+        store.clear(key).unwrap();
+        assert_eq!(store.load(key).unwrap(), None);
+        // Clearing a missing key is not an error.
+        store.clear(key).unwrap();
+    }
+
+    #[test]
+    fn keys_do_not_collide_after_sanitizing() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::new(dir.path());
+        let mut progress = Progress::default();
+        progress.mark_done(3, 1);
+        store.save("progress:a:m", &progress).unwrap();
+        assert_eq!(store.load("progress:b:m").unwrap(), None);
+
+        store.save("progress:c:gpt-3.5", &progress).unwrap();
+        assert_eq!(store.load("progress:c:gpt-3_5").unwrap(), None);
+        assert_eq!(store.load("progress:c:GPT-3.5").unwrap(), None);
+        assert_eq!(store.load("progress:c:gpt-3.5").unwrap(), Some(progress));
+        assert_eq!(
+            store.path("progress:c:gpt-3.5").file_name().unwrap(),
+            "progress_3ac_3agpt-3_2e5.json"
+        );
+    }
+
+    #[test]
+    fn resumed_run_skips_done_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::new(dir.path());
+        let mut progress = Progress::default();
+        progress.mark_done(0, 5);
+        progress.mark_done(1, 4);
+        progress.mark_failed(2, String::from("api: 500"));
+        store.save("job", &progress).unwrap();
+
+        let progress = store.load("job").unwrap().unwrap();
+        let pending: Vec<usize> = (0..4)
+            .filter(|i| !progress.should_skip(*i, false))
+            .collect();
+        assert_eq!(pending, vec![2, 3]);
+        assert_eq!(progress.last_completed, Some(1));
+    }
+
+    #[test]
+    fn drip_mode_skips_failed_chunks() {
+        let mut progress = Progress::default();
+        progress.mark_done(0, 5);
+        progress.mark_failed(1, String::from("api: 500"));
+
+        let pending: Vec<usize> = (0..3).filter(|i| !progress.should_skip(*i, true)).collect();
+        assert_eq!(pending, vec![2]);
+        assert!(progress.is_attempted(1));
+        assert!(!progress.is_done(1));
+    }
+}
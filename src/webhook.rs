@@ -0,0 +1,225 @@
+use crate::chunk::{apply_strategy, load_chunks, Chunk, ChunkStrategy};
+use crate::csv::CsvSink;
+use crate::pair::{PairField, QaPair};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Csv,
+    Jsonl,
+}
+
+impl OutputFormat {
+    /// Picks JSONL when the `Accept` header asks for it and CSV otherwise.
+    pub fn from_accept(accept: Option<&str>) -> Self {
+        match accept {
+            Some(accept)
+                if accept.contains("application/jsonl")
+                    || accept.contains("application/x-ndjson") =>
+            {
+                OutputFormat::Jsonl
+            }
+            _ => OutputFormat::Csv,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Csv => "text/csv",
+            OutputFormat::Jsonl => "application/jsonl",
+        }
+    }
+}
+
+/// Case-insensitive lookup of a request header.
+pub fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+/// Turns a request body into chunks. JSON bodies must be an array of
+/// pre-chunked strings; any other body is detected as either a JSON array or
+/// raw text. Either way the chunks are then cut according to `strategy`.
+pub fn parse_body(
+    content_type: Option<&str>,
+    body: &[u8],
+    strategy: &ChunkStrategy,
+) -> anyhow::Result<Vec<Chunk>> {
+    let text = std::str::from_utf8(body)
+        .map_err(|e| anyhow::anyhow!("request body is not valid UTF-8: {}", e))?;
+    match content_type {
+        Some(ct) if ct.contains("application/json") => {
+            let sections = serde_json::from_str(text)
+                .map_err(|e| anyhow::anyhow!("expected a JSON array of strings: {}", e))?;
+            Ok(apply_strategy(sections, strategy))
+        }
+        _ => Ok(load_chunks(text, strategy)),
+    }
+}
+
+/// Renders pairs as CSV or JSONL. The CSV gains a leading Section column
+/// when any pair has a heading path, and ends with the model that generated
+/// each pair; JSONL records carry every `QaPair` field.
+pub fn render(rows: &[QaPair], format: OutputFormat) -> String {
+    let mut out = String::new();
+    match format {
+        OutputFormat::Csv => {
+            let mut columns = vec![PairField::Question, PairField::Answer, PairField::Model];
+            if rows.iter().any(|row| row.section.is_some()) {
+                columns.insert(0, PairField::Section);
+            }
+            let mut csv = CsvSink::new(Vec::new(), &columns, true);
+            csv.write_header().expect("writing to memory cannot fail");
+            for row in rows {
+                csv.write_pair(row).expect("writing to memory cannot fail");
+            }
+            out = String::from_utf8(csv.into_inner()).expect("CSV is built from strings");
+        }
+        OutputFormat::Jsonl => {
+            for row in rows {
+                out.push_str(&serde_json::to_string(row).expect("failed to serialize row"));
+                out.push('\n');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::GenerationParams;
+    use crate::reply::ReplyPair;
+    use chrono::{TimeZone, Utc};
+
+    fn texts(chunks: &[Chunk]) -> Vec<&str> {
+        chunks.iter().map(|chunk| chunk.text.as_str()).collect()
+    }
+
+    fn pair(question: &str, answer: &str, headings: &[&str]) -> QaPair {
+        let chunk = Chunk {
+            text: String::from("Pods run containers."),
+            headings: headings.iter().map(|h| h.to_string()).collect(),
+        };
+        let reply = ReplyPair {
+            question: question.to_string(),
+            answer: answer.to_string(),
+            question_type: None,
+            difficulty: None,
+        };
+        let generated_at = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        QaPair::from_reply(
+            reply,
+            &chunk,
+            &GenerationParams::default(),
+            "2",
+            generated_at,
+        )
+    }
+
+    #[test]
+    fn parses_json_bodies_as_pre_chunked_strings() {
+        let body = br#"["First chunk.\n\nStill first.", "Second chunk."]"#;
+        let chunks = parse_body(
+            Some("application/json; charset=utf-8"),
+            body,
+            &ChunkStrategy::BlankLine,
+        )
+        .unwrap();
+        assert_eq!(
+            texts(&chunks),
+            vec!["First chunk.\n\nStill first.", "Second chunk."]
+        );
+
+        let err = parse_body(
+            Some("application/json"),
+            b"not json",
+            &ChunkStrategy::BlankLine,
+        );
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn splits_raw_text_on_blank_lines() {
+        let chunks = parse_body(
+            Some("text/plain"),
+            b"First section.\n\nSecond section.\n",
+            &ChunkStrategy::BlankLine,
+        )
+        .unwrap();
+        assert_eq!(
+            texts(&chunks),
+            vec!["First section.\n", "Second section.\n"]
+        );
+
+        // Without a JSON content type, an array body is still detected.
+        let chunks = parse_body(None, br#"["a", "b"]"#, &ChunkStrategy::BlankLine).unwrap();
+        assert_eq!(texts(&chunks), vec!["a", "b"]);
+
+        assert!(parse_body(None, &[0xff, 0xfe], &ChunkStrategy::BlankLine).is_err());
+    }
+
+    #[test]
+    fn picks_jsonl_only_when_asked() {
+        assert_eq!(OutputFormat::from_accept(None), OutputFormat::Csv);
+        assert_eq!(
+            OutputFormat::from_accept(Some("text/csv")),
+            OutputFormat::Csv
+        );
+        assert_eq!(OutputFormat::from_accept(Some("*/*")), OutputFormat::Csv);
+        assert_eq!(
+            OutputFormat::from_accept(Some("application/jsonl")),
+            OutputFormat::Jsonl
+        );
+        assert_eq!(
+            OutputFormat::from_accept(Some("application/x-ndjson, text/csv;q=0.5")),
+            OutputFormat::Jsonl
+        );
+    }
+
+    #[test]
+    fn renders_csv_with_a_section_column_when_needed() {
+        let model = crate::params::DEFAULT_MODEL;
+        let csv = render(
+            &[pair("What runs?", "Pods, mostly.", &[])],
+            OutputFormat::Csv,
+        );
+        assert_eq!(
+            csv,
+            format!(
+                "Question,Answer,Model\r\nWhat runs?,\"Pods, mostly.\",{}\r\n",
+                model
+            )
+        );
+
+        let rows = [
+            pair("What runs?", "Pods.", &["Basics", "Pods"]),
+            pair("Why?", "Because.", &[]),
+        ];
+        let csv = render(&rows, OutputFormat::Csv);
+        let lines: Vec<&str> = csv.split("\r\n").collect();
+        assert_eq!(lines[0], "Section,Question,Answer,Model");
+        assert_eq!(
+            lines[1],
+            format!("Basics > Pods,What runs?,Pods.,{}", model)
+        );
+        assert_eq!(lines[2], format!(",Why?,Because.,{}", model));
+    }
+
+    #[test]
+    fn renders_one_json_object_per_line() {
+        let rows = [
+            pair("What runs?", "Pods.", &[]),
+            pair("Why?", "Because.", &[]),
+        ];
+        let jsonl = render(&rows, OutputFormat::Jsonl);
+        let parsed: Vec<QaPair> = jsonl
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(parsed, rows);
+        assert!(jsonl.ends_with('\n'));
+        assert!(render(&[], OutputFormat::Jsonl).is_empty());
+    }
+}